@@ -1,21 +1,137 @@
-use std::{sync::mpsc::{Receiver}, iter::zip};
+use std::{sync::mpsc::{Receiver, TryRecvError}, collections::VecDeque};
 
-use crate::{types::Samp, tts::Speaker, sample_library::SampleLibrary};
+use crate::{types::{Samp, sample_rate, ms_to_samples}, tts::Synthesizer};
+use crate::sample_library::{SampleLibrary, Selector, Category, INTENSITIES};
+use crate::markup::{self, Segment};
+use crate::mixer::{BusId, Cue, FadeOut};
+use crate::events::Hit;
+use crate::output::{Marker, Sound};
+
+#[derive(Clone, Copy, Debug)]
+pub enum Voice {
+    PlayByPlay, // Reads out the plays
+    Colour // Fills lulls and reacts to big plays
+}
+
+impl Voice {
+    pub fn name(self) -> &'static str {
+        return match self {
+            Voice::PlayByPlay => "play_by_play",
+            Voice::Colour => "colour"
+        };
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Pace {
+    Normal,
+    Excited, // Big plays, read a bit faster
+    Measured // Intros and the like, read a bit slower
+}
+
+impl Pace {
+    fn speedup(self) -> f32 {
+        return match self {
+            Pace::Normal => 1.0,
+            Pace::Excited => 1.2,
+            Pace::Measured => 0.85
+        };
+    }
+}
+
+// Which side of the ground a crowd reaction comes from
+#[derive(Clone, Copy, Debug)]
+pub enum Stands {
+    Home,
+    Away
+}
+
+impl Stands {
+    fn pan(self) -> f32 {
+        return match self {
+            Stands::Home => 0.6,
+            Stands::Away => -0.6
+        };
+    }
+
+    pub fn name(self) -> &'static str {
+        return match self {
+            Stands::Home => "home",
+            Stands::Away => "away"
+        };
+    }
+}
+
+// Bat cracks sit a little off-centre, as if the mic's behind the plate
+const THWACK_PAN: f32 = -0.2;
+
+#[derive(Clone, Copy, Debug)]
+pub enum MusicCue {
+    Intro, // Theme under the pregame announcement, until told to stop
+    Stretch, // Organ for the seventh-inning stretch
+    Outro // Under the final score
+}
+
+impl MusicCue {
+    // Where to find it in the library, and whether it keeps going until stopped
+    fn sound(self) -> (Category, &'static str, bool) {
+        return match self {
+            MusicCue::Intro => (Category::Jingle, "intro", true),
+            MusicCue::Stretch => (Category::Organ, "stretch", false),
+            MusicCue::Outro => (Category::Jingle, "outro", false)
+        };
+    }
+
+    pub fn name(self) -> &'static str {
+        return self.sound().1;
+    }
+}
+
+const MUSIC_FADE_IN_MS: u64 = 500;
+const MUSIC_FADE_OUT_MS: u64 = 2500;
 
 pub enum AnnounceEvent {
     Beat(), // Short delay.
-    Thwack(f32), // Baseball bat sound
-    Message(String), // TTS message
+    Thwack(Hit), // Baseball bat sound, played under whatever comes next
+    Cheer(f32, Stands), // Crowd reactions, at the given intensity
+    Groan(f32, Stands),
+    Message(Voice, String, Pace), // TTS message, may contain SSML markup
+    MusicStart(MusicCue), // Fade in some music
+    MusicStop(), // Fade out whatever music's playing
     Delay(u64), // Delay, in samples
+    Clock(u64), // Game time, in samples since the first game event
+    NowPlaying(String), // New description of the game for listeners, like the score
+    Chapter(String), // Start of a part of the game worth skipping to, like an inning
     Finish() // Stop broadcasting
 }
 
-const BEAT_LENGTH: u64 = 1024; // ~46ms @ 22050 Hz
+// Speeds up speech when the broadcast falls behind the game clock
+pub struct AdaptiveRate {
+    pub max_speedup: f32,
+    pub catch_up: f32, // Seconds behind at which we reach max_speedup
+}
+
+const BEAT_LENGTH_MS: u64 = 46;
 const ANNOUNCE_VOLUME: f32 = 1.0;
 
+// Fallback intensity tag, gain and playback rate range for each sort of hit. Harder hits use
+// bigger samples, played slower so they're deeper.
+fn hit_sound(hit: Hit) -> (&'static str, f32, (f32, f32)) {
+    return match hit {
+        Hit::Foul => ("soft", 0.3, (1.1, 1.25)),
+        Hit::WeakGrounder => ("soft", 0.5, (1.05, 1.2)),
+        Hit::LineDrive => ("medium", 0.9, (0.95, 1.05)),
+        Hit::DeepFly => ("loud", 1.1, (0.9, 0.97)),
+        Hit::HomeRun => ("loud", 1.3, (0.8, 0.88))
+    };
+}
+
+fn intensity(level: f32) -> &'static str {
+    return INTENSITIES[((level * 2.0) as usize).min(INTENSITIES.len() - 1)];
+}
+
 enum ChannelState {
     Waiting,
-    Sampling,
     Announcing,
     Idle,
     Finished
@@ -25,24 +141,57 @@ pub struct AnnounceChannel<'a> {
     state: ChannelState,
     wait_left: u64,
     rx: Receiver<AnnounceEvent>,
-    speaker: Speaker,
-    thwacks: &'a SampleLibrary,
+    play_by_play: Box<dyn Synthesizer>,
+    colour: Box<dyn Synthesizer>,
+    voice: Voice,
+    // Hand markup to the speaker rather than acting it out here
+    ssml: bool,
+    // Rest of the current message, when acting out markup
+    segments: VecDeque<Segment>,
+    message_speedup: f32,
+    adaptive: Option<AdaptiveRate>,
+    // Keep going with silence when there's nothing to say yet, rather than waiting for the game
+    live: bool,
+    position: u64, // Samples output so far
+    game_clock: u64,
+    sfx: &'a SampleLibrary,
+    selector: Selector,
 
-    volume: f32,
-    current_sample: Option<&'a[Samp]>,
+    // Sounds for the mixer, and how far into the current block we are
+    cues: Vec<Cue<'a>>,
+    fades: Vec<FadeOut>,
+    block_offset: usize,
+    // Things for the output to know about, with the frame they happen at
+    markers: Vec<(u64, Marker)>,
+    // Message being spoken, and the frame it started at
+    line: Option<(u64, Voice, String)>,
 }
 
 impl<'a> AnnounceChannel<'a> {
 
-    pub fn new(rx: Receiver<AnnounceEvent>, thwacks: &'a mut SampleLibrary) -> AnnounceChannel<'a> {
+    pub fn new(rx: Receiver<AnnounceEvent>, sfx: &'a SampleLibrary, selector: Selector, play_by_play: Box<dyn Synthesizer>,
+        colour: Box<dyn Synthesizer>, ssml: bool, adaptive: Option<AdaptiveRate>, live: bool) -> AnnounceChannel<'a> {
         return AnnounceChannel {
             state: ChannelState::Idle,
             wait_left: 0,
-            volume: 1.0,
             rx: rx,
-            speaker: Speaker::new(),
-            thwacks: thwacks,
-            current_sample: None
+            play_by_play: play_by_play,
+            colour: colour,
+            voice: Voice::PlayByPlay,
+            ssml: ssml,
+            segments: VecDeque::new(),
+            message_speedup: 1.0,
+            adaptive: adaptive,
+            live: live,
+            position: 0,
+            game_clock: 0,
+            sfx: sfx,
+            selector: selector,
+            cues: Vec::new(),
+            fades: Vec::new(),
+            block_offset: 0,
+            markers: Vec::new(),
+            line: None
         };
     }
 
@@ -51,14 +200,15 @@ impl<'a> AnnounceChannel<'a> {
         let sample_target: usize = buf.len() as usize;
 
         while samples_filled < sample_target {
+            self.block_offset = samples_filled;
             match self.state {
                 ChannelState::Waiting => samples_filled += self.wait(&mut buf[samples_filled..]),
                 ChannelState::Announcing => samples_filled += self.announce(&mut buf[samples_filled..]),
                 ChannelState::Idle => samples_filled += self.idle(&mut buf[samples_filled..]),
-                ChannelState::Sampling => samples_filled += self.sample(&mut buf[samples_filled..]),
                 ChannelState::Finished => break
             }
         }
+        self.position += samples_filled as u64;
         return samples_filled;
     }
 
@@ -71,20 +221,72 @@ impl<'a> AnnounceChannel<'a> {
             buf[..(self.wait_left as usize)].fill(0.0);
             let filled = self.wait_left as usize;
             self.wait_left = 0;
-            self.get_next_state();
+            // Whatever comes next starts after the wait
+            self.block_offset += filled;
+            self.continue_message();
             return filled;
         }
     }
 
+    fn speaker(&mut self) -> &mut dyn Synthesizer {
+        return match self.voice {
+            Voice::PlayByPlay => self.play_by_play.as_mut(),
+            Voice::Colour => self.colour.as_mut()
+        };
+    }
+
+    fn catch_up_speedup(&self) -> f32 {
+        return match &self.adaptive {
+            None => 1.0,
+            Some(adaptive) => {
+                let behind = self.position.saturating_sub(self.game_clock) as f32 / sample_rate().get() as f32;
+                1.0 + (adaptive.max_speedup - 1.0) * (behind / adaptive.catch_up).min(1.0)
+            }
+        };
+    }
+
     fn announce(&mut self, buf: &mut [Samp]) -> usize {
-        if self.speaker.is_speaking() {
-            return self.speaker.next(buf);
+        let speaker = self.speaker();
+        if speaker.is_speaking() {
+            return speaker.next(buf);
         } else {
-            self.get_next_state();
+            self.continue_message();
             return 0;
         }
     }
 
+    // Move on to the next piece of a marked-up message, or the next event
+    fn continue_message(&mut self) {
+        match self.segments.pop_front() {
+            Some(Segment::Text(text, rate)) => {
+                let speedup = self.message_speedup * rate;
+                let speaker = self.speaker();
+                let prosody = speaker.prosody().faster(speedup);
+                if let Err(err) = speaker.say(&text, prosody) {
+                    eprintln!("Skipping line {:?}: {}", text, err);
+                }
+                self.state = ChannelState::Announcing;
+            },
+            Some(Segment::Break(ms)) => {
+                self.wait_left = ms_to_samples(ms as u64);
+                self.state = ChannelState::Waiting;
+            },
+            None => {
+                self.end_line();
+                self.get_next_state();
+            }
+        }
+    }
+
+    fn end_line(&mut self) {
+        if let Some((start, voice, text)) = self.line.take() {
+            let frames = self.now() - start;
+            if frames > 0 {
+                self.mark(Marker::Line { voice: voice, text: text, frames: frames });
+            }
+        }
+    }
+
     fn idle(&mut self, buf: &mut [Samp]) -> usize {
         if matches!(self.state, ChannelState::Idle) {
             self.get_next_state();
@@ -96,50 +298,145 @@ impl<'a> AnnounceChannel<'a> {
         return buf.len();
     }
 
-    fn sample(&mut self, buf: &mut [Samp]) -> usize {
-        return match self.current_sample {
-            None => {
-                self.get_next_state(); 0
-            },
-            Some(sample) => {
-                if buf.len() >= sample.len() {
-                    for (s, b) in zip(sample, buf) {
-                        *b = *s * self.volume;
-                    }
-                    self.current_sample = None;
-                    sample.len()
-                } else {
-                    // buf.copy_from_slice(&sample[..buf.len()]);
-                    let samples_copied = buf.len();
-                    for (s, b) in zip(sample, buf) {
-                        *b = *s * self.volume;
-                    }
-                    self.current_sample = Some(&sample[samples_copied..]);
-                    samples_copied
-                }
-            }
-        };
-    }
-
     fn get_next_state(&mut self)
     {
-        /*
-        self.state = self.rx.try_recv().map_or(ChannelState::Idle, |ev| {
-            */
-        self.state = self.rx.recv().map_or(ChannelState::Finished, |ev|{
-            return match ev {
-                AnnounceEvent::Beat() => { self.wait_left = BEAT_LENGTH; ChannelState::Waiting },
-                AnnounceEvent::Thwack(t) => {
-                    self.current_sample = Some(self.thwacks.get(fastrand::usize(..self.thwacks.len())));
-                    self.volume = t; 
-                    ChannelState::Sampling
+        // Cues and clock updates don't take any time, so keep going until something does
+        loop {
+            let ev = if self.live {
+                match self.rx.try_recv() {
+                    Ok(ev) => ev,
+                    // idle() fills in until there is something
+                    Err(TryRecvError::Empty) => { self.state = ChannelState::Idle; return; },
+                    Err(TryRecvError::Disconnected) => { self.state = ChannelState::Finished; return; }
+                }
+            } else {
+                // The game thread always ends with Finish, or hangs up
+                match self.rx.recv() {
+                    Ok(ev) => ev,
+                    Err(_) => { self.state = ChannelState::Finished; return; }
+                }
+            };
+            self.state = match ev {
+                AnnounceEvent::Beat() => { self.wait_left = ms_to_samples(BEAT_LENGTH_MS); ChannelState::Waiting },
+                AnnounceEvent::Thwack(hit) => { self.thwack(hit); continue; },
+                AnnounceEvent::Cheer(c, stands) => {
+                    self.cue(BusId::Crowd, Category::CrowdCheer, &[intensity(c)], c, 1.0, stands.pan());
+                    self.mark(Marker::Sound(Sound::Cheer(stands)));
+                    continue;
+                },
+                AnnounceEvent::Groan(g, stands) => {
+                    self.cue(BusId::Crowd, Category::CrowdGroan, &[intensity(g)], g, 1.0, stands.pan());
+                    self.mark(Marker::Sound(Sound::Groan(stands)));
+                    continue;
+                },
+                AnnounceEvent::MusicStart(music) => {
+                    let (category, tag, looped) = music.sound();
+                    self.mark(Marker::Sound(Sound::MusicStart(music)));
+                    if let Some(sample) = self.selector.pick(self.sfx, category, &[tag]) {
+                        self.cues.push(Cue {
+                            offset: self.block_offset,
+                            bus: BusId::Music,
+                            sample: sample,
+                            gain: 1.0,
+                            rate: 1.0,
+                            pan: 0.0,
+                            fade_in: ms_to_samples(MUSIC_FADE_IN_MS) as usize,
+                            looped: looped
+                        });
+                    }
+                    continue;
+                },
+                AnnounceEvent::MusicStop() => {
+                    self.mark(Marker::Sound(Sound::MusicStop));
+                    self.fades.push(FadeOut {
+                        offset: self.block_offset,
+                        bus: BusId::Music,
+                        length: ms_to_samples(MUSIC_FADE_OUT_MS) as usize
+                    });
+                    continue;
                 },
                 AnnounceEvent::Delay(d) => { self.wait_left = d; ChannelState::Waiting },
-                AnnounceEvent::Message(s) => { self.speaker.say(&s); ChannelState::Announcing },
+                AnnounceEvent::Message(v, s, pace) => {
+                    self.voice = v;
+                    self.line = Some((self.now(), v, markup::plain_text(&s)));
+                    let speedup = pace.speedup() * self.catch_up_speedup();
+                    if self.ssml {
+                        let speaker = self.speaker();
+                        let prosody = speaker.prosody().faster(speedup);
+                        if let Err(err) = speaker.say_ssml(&markup::to_ssml(&s), prosody) {
+                            eprintln!("Skipping line {:?}: {}", s, err);
+                        }
+                    } else {
+                        // Picked up by announce() once the speaker is free
+                        self.segments = markup::parse(&s).into();
+                        self.message_speedup = speedup;
+                    }
+                    ChannelState::Announcing
+                },
+                AnnounceEvent::Clock(t) => { self.game_clock = t; continue; },
+                AnnounceEvent::NowPlaying(text) => { self.mark(Marker::NowPlaying(text)); continue; },
+                AnnounceEvent::Chapter(title) => { self.mark(Marker::Chapter(title)); continue; },
                 AnnounceEvent::Finish() => ChannelState::Finished
-            }
-        });
+            };
+            return;
+        }
+    }
+
+    fn thwack(&mut self, hit: Hit) {
+        let grade = hit.name();
+        let (intensity, gain, (slowest, fastest)) = hit_sound(hit);
+        let category = if hit == Hit::Foul && self.sfx.len(Category::Foul) > 0 {
+            Category::Foul
+        } else {
+            Category::Thwack
+        };
+        // Samples tagged for this sort of hit if there are any, or just ones the right size
+        let tag = if self.sfx.has_tagged(category, &[grade]) { grade } else { intensity };
+        let rate = slowest + fastrand::f32() * (fastest - slowest);
+        self.cue(BusId::Sfx, category, &[tag], gain, rate, THWACK_PAN);
+        self.mark(Marker::Sound(Sound::Thwack(hit)));
+    }
+
+    // Have the mixer start a sound from the category at the current point in the block
+    fn cue(&mut self, bus: BusId, category: Category, tags: &[&str], gain: f32, rate: f32, pan: f32) {
+        if let Some(sample) = self.selector.pick(self.sfx, category, tags) {
+            self.cues.push(Cue {
+                offset: self.block_offset,
+                bus: bus,
+                sample: sample,
+                gain: gain,
+                rate: rate,
+                pan: pan,
+                fade_in: 0,
+                looped: false
+            });
+        }
+    }
+
+    // Frame we're at in the broadcast
+    fn now(&self) -> u64 {
+        return self.position + self.block_offset as u64;
+    }
+
+    fn mark(&mut self, marker: Marker) {
+        self.markers.push((self.now(), marker));
+    }
+
+    // Whether the game's over and everything's been said
+    pub fn is_finished(&self) -> bool {
+        return matches!(self.state, ChannelState::Finished);
+    }
+
+    pub fn take_markers(&mut self) -> Vec<(u64, Marker)> {
+        return std::mem::take(&mut self.markers);
+    }
+
+    pub fn take_cues(&mut self) -> Vec<Cue<'a>> {
+        return std::mem::take(&mut self.cues);
+    }
 
+    pub fn take_fades(&mut self) -> Vec<FadeOut> {
+        return std::mem::take(&mut self.fades);
     }
 
 }
\ No newline at end of file
@@ -0,0 +1,106 @@
+use std::io::Write;
+use std::path::Path;
+
+use crate::output::{Destination, Marker, OutputError, Sink, timestamp};
+use crate::types::{Samp, sample_rate};
+
+// Chapters for skipping around a finished broadcast. WAV and FLAC files get them built in; for
+// everything else they can go in a sidecar file, either a cue sheet or Ogg chapter comments that
+// tools like vorbiscomment can add to the file afterwards.
+
+const CUE_FRAMES_PER_SECOND: u64 = 75;
+
+#[derive(Clone, Copy)]
+pub enum ChapterFormat {
+    CueSheet,
+    OggComments, // CHAPTER001=00:00:00.000 and CHAPTER001NAME=..., one to a line
+}
+
+impl ChapterFormat {
+    pub fn from_path(path: &Path) -> Option<ChapterFormat> {
+        return match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "cue" => Some(ChapterFormat::CueSheet),
+            "txt" => Some(ChapterFormat::OggComments),
+            _ => None
+        };
+    }
+}
+
+pub struct ChapterSink {
+    destination: Box<dyn Destination>,
+    format: ChapterFormat,
+    count: usize,
+}
+
+impl ChapterSink {
+
+    // The cue sheet points at the audio file, and takes its title and performer from the comments
+    pub fn new(mut destination: Box<dyn Destination>, format: ChapterFormat, audio_file: Option<&Path>,
+        comments: &[(String, String)]) -> Result<Self, OutputError> {
+        if let ChapterFormat::CueSheet = format {
+            let comment = |field: &str| comments.iter().find(|(f, _)| f == field).map(|(_, v)| v.clone());
+            if let Some(title) = comment("TITLE") {
+                write!(destination, "TITLE {}\n", quote(&title))?;
+            }
+            if let Some(artist) = comment("ARTIST") {
+                write!(destination, "PERFORMER {}\n", quote(&artist))?;
+            }
+            let file = audio_file.and_then(|p| p.file_name()).and_then(|n| n.to_str()).unwrap_or("-");
+            write!(destination, "FILE {} WAVE\n", quote(file))?;
+        }
+        return Ok(ChapterSink {
+            destination: destination,
+            format: format,
+            count: 0,
+        });
+    }
+}
+
+impl Sink for ChapterSink {
+    fn write(&mut self, _buf: &[Samp]) -> Result<(), OutputError> {
+        return Ok(());
+    }
+
+    fn mark(&mut self, frame: u64, marker: &Marker) -> Result<(), OutputError> {
+        let title = match marker {
+            Marker::Chapter(title) => title,
+            _ => return Ok(())
+        };
+        self.count += 1;
+        match self.format {
+            ChapterFormat::CueSheet => {
+                // Tracks have to start at the very beginning
+                let frame = if self.count == 1 { 0 } else { frame };
+                let cue_frames = frame * CUE_FRAMES_PER_SECOND / sample_rate().get() as u64;
+                write!(self.destination, "  TRACK {:02} AUDIO\n    TITLE {}\n    INDEX 01 {:02}:{:02}:{:02}\n",
+                    self.count, quote(title),
+                    cue_frames / CUE_FRAMES_PER_SECOND / 60, cue_frames / CUE_FRAMES_PER_SECOND % 60,
+                    cue_frames % CUE_FRAMES_PER_SECOND)?;
+            },
+            ChapterFormat::OggComments => {
+                for (field, value) in chapter_comments(self.count, frame, title) {
+                    write!(self.destination, "{}={}\n", field, value)?;
+                }
+            }
+        }
+        self.destination.flush()?;
+        return Ok(());
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), OutputError> {
+        self.destination.flush()?;
+        return Ok(());
+    }
+}
+
+// The Vorbis comments for the number'th chapter (counting from 1)
+pub fn chapter_comments(number: usize, frame: u64, title: &str) -> [(String, String); 2] {
+    return [
+        (format!("CHAPTER{:03}", number), timestamp(frame, '.')),
+        (format!("CHAPTER{:03}NAME", number), title.to_string()),
+    ];
+}
+
+fn quote(text: &str) -> String {
+    return format!("\"{}\"", text.replace('"', "'"));
+}
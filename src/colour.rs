@@ -0,0 +1,47 @@
+use crate::events::Team;
+
+// Lines for the colour commentator. {team} is replaced with a team's short name.
+
+const BIG_PLAY_LINES: &[&str] = &[
+    "Oh, what a play!",
+    "Now that's what the fans came out to see.",
+    "You love to see it.",
+    "The {team} faithful are on their feet!",
+    "That one's going in the highlight reel.",
+    "Big moment for the {team} there.",
+];
+
+const LULL_LINES: &[&str] = &[
+    "Lovely night for blaseball.",
+    "The pitcher taking their time out there.",
+    "You can feel the tension in the stands.",
+    "Not a lot happening, but that's blaseball for you.",
+    "The {team} need something to get going here.",
+    "Reminder, folks: the concession stands are open. Mind the peanuts.",
+];
+
+const INNING_LINES: &[&str] = &[
+    "Plenty of game left to play.",
+    "The {team} will be happy with that inning.",
+    "Let's see if the {team} can answer back.",
+];
+
+// Number of quiet plays in a row before the colour commentator chimes in
+pub const LULL_LENGTH: u32 = 8;
+
+fn pick(lines: &[&str], team: &Team) -> String {
+    let line = lines[fastrand::usize(..lines.len())];
+    return line.replace("{team}", &team.short_name);
+}
+
+pub fn big_play(team: &Team) -> String {
+    return pick(BIG_PLAY_LINES, team);
+}
+
+pub fn lull(team: &Team) -> String {
+    return pick(LULL_LINES, team);
+}
+
+pub fn inning_break(team: &Team) -> String {
+    return pick(INNING_LINES, team);
+}
@@ -0,0 +1,325 @@
+use std::f64::consts::PI;
+
+use crate::loudness::{db_to_gain, gain_to_db};
+use crate::types::{Samp, CHANNELS, sample_rate};
+
+// Effects that can go on any mixer bus. They all work on interleaved stereo.
+pub trait Effect {
+    fn process(&mut self, buf: &mut [Samp]);
+
+    // Frames it keeps making sound for after its input goes quiet
+    fn tail(&self) -> usize {
+        return 0;
+    }
+}
+
+// Effects run one after another
+pub struct Chain {
+    effects: Vec<Box<dyn Effect>>,
+}
+
+impl Chain {
+
+    pub fn new() -> Self {
+        return Chain { effects: Vec::new() };
+    }
+
+    pub fn with(mut self, effect: impl Effect + 'static) -> Self {
+        self.effects.push(Box::new(effect));
+        return self;
+    }
+
+    pub fn process(&mut self, buf: &mut [Samp]) {
+        for effect in self.effects.iter_mut() {
+            effect.process(buf);
+        }
+    }
+
+    pub fn tail(&self) -> usize {
+        return self.effects.iter().map(|e| e.tail()).sum();
+    }
+}
+
+// Second order IIR filter section
+pub struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3], // a[0] is always 1
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+
+    pub fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        return Biquad { b: b, a: a, x: [0.0; 2], y: [0.0; 2] };
+    }
+
+    // Filters from the RBJ audio EQ cookbook
+    pub fn low_pass(freq: f64, q: f64) -> Self {
+        let (cos, alpha) = cookbook(freq, q);
+        return Biquad::normalized([(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0], [1.0 + alpha, -2.0 * cos, 1.0 - alpha]);
+    }
+
+    pub fn high_pass(freq: f64, q: f64) -> Self {
+        let (cos, alpha) = cookbook(freq, q);
+        return Biquad::normalized([(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0], [1.0 + alpha, -2.0 * cos, 1.0 - alpha]);
+    }
+
+    fn normalized(b: [f64; 3], a: [f64; 3]) -> Self {
+        return Biquad::new([b[0] / a[0], b[1] / a[0], b[2] / a[0]], [1.0, a[1] / a[0], a[2] / a[0]]);
+    }
+
+    pub fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[1] * self.y[0] - self.a[2] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        return y;
+    }
+}
+
+fn cookbook(freq: f64, q: f64) -> (f64, f64) {
+    let w0 = 2.0 * PI * freq.min(sample_rate().get() as f64 * 0.49) / sample_rate().get() as f64;
+    return (w0.cos(), w0.sin() / (2.0 * q));
+}
+
+// Freeverb-style reverb: parallel damped combs into series allpasses, for each side
+const COMB_TUNING: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_TUNING: [usize; 4] = [556, 441, 341, 225];
+const TUNING_RATE: f32 = 44100.0; // Rate the delay lengths above are for
+const STEREO_SPREAD: usize = 23;
+const REVERB_INPUT_GAIN: f32 = 0.015;
+
+struct Comb {
+    buf: Vec<Samp>,
+    index: usize,
+    store: Samp,
+}
+
+impl Comb {
+    fn process(&mut self, input: Samp, feedback: f32, damping: f32) -> Samp {
+        let out = self.buf[self.index];
+        self.store = out * (1.0 - damping) + self.store * damping;
+        self.buf[self.index] = input + self.store * feedback;
+        self.index = (self.index + 1) % self.buf.len();
+        return out;
+    }
+}
+
+struct Allpass {
+    buf: Vec<Samp>,
+    index: usize,
+}
+
+impl Allpass {
+    fn process(&mut self, input: Samp) -> Samp {
+        let delayed = self.buf[self.index];
+        self.buf[self.index] = input + delayed * 0.5;
+        self.index = (self.index + 1) % self.buf.len();
+        return delayed - input;
+    }
+}
+
+pub struct Reverb {
+    combs: [Vec<Comb>; CHANNELS],
+    allpasses: [Vec<Allpass>; CHANNELS],
+    feedback: f32,
+    damping: f32,
+    wet: f32,
+    dry: f32,
+    tail: usize,
+}
+
+impl Reverb {
+
+    // Room size and damping go from 0 to 1; bigger rooms ring for longer
+    pub fn new(room_size: f32, damping: f32, wet: f32, dry: f32) -> Self {
+        let scale = sample_rate().get() as f32 / TUNING_RATE;
+        let length = |tuning: usize, side: usize| (((tuning + side * STEREO_SPREAD) as f32 * scale) as usize).max(1);
+        let feedback = room_size.clamp(0.0, 1.0) * 0.28 + 0.7;
+        // Time for the longest comb to die away by 60dB
+        let longest = length(COMB_TUNING[COMB_TUNING.len() - 1], 1);
+        let tail = (longest as f32 * -3.0 / feedback.log10()) as usize;
+        return Reverb {
+            combs: [0, 1].map(|side| COMB_TUNING.iter()
+                .map(|t| Comb { buf: vec![0.0; length(*t, side)], index: 0, store: 0.0 })
+                .collect()),
+            allpasses: [0, 1].map(|side| ALLPASS_TUNING.iter()
+                .map(|t| Allpass { buf: vec![0.0; length(*t, side)], index: 0 })
+                .collect()),
+            feedback: feedback,
+            damping: damping.clamp(0.0, 1.0) * 0.4,
+            wet: wet,
+            dry: dry,
+            tail: tail,
+        };
+    }
+}
+
+impl Effect for Reverb {
+    fn process(&mut self, buf: &mut [Samp]) {
+        for frame in buf.chunks_exact_mut(CHANNELS) {
+            let input = frame.iter().sum::<Samp>() * REVERB_INPUT_GAIN;
+            for (side, s) in frame.iter_mut().enumerate() {
+                let mut out: Samp = self.combs[side].iter_mut()
+                    .map(|c| c.process(input, self.feedback, self.damping))
+                    .sum();
+                for allpass in self.allpasses[side].iter_mut() {
+                    out = allpass.process(out);
+                }
+                *s = *s * self.dry + out * self.wet;
+            }
+        }
+    }
+
+    fn tail(&self) -> usize {
+        return self.tail;
+    }
+}
+
+// Feed-forward compressor, with both sides turned down together
+pub struct Compressor {
+    threshold_db: f32,
+    ratio: f32,
+    makeup_db: f32,
+    attack: f32,
+    release: f32,
+    envelope_db: f32,
+}
+
+impl Compressor {
+
+    pub fn new(threshold_db: f32, ratio: f32, attack_ms: f32, release_ms: f32, makeup_db: f32) -> Self {
+        return Compressor {
+            threshold_db: threshold_db,
+            ratio: ratio.max(1.0),
+            makeup_db: makeup_db,
+            attack: smoothing(attack_ms),
+            release: smoothing(release_ms),
+            envelope_db: -120.0,
+        };
+    }
+}
+
+impl Effect for Compressor {
+    fn process(&mut self, buf: &mut [Samp]) {
+        for frame in buf.chunks_exact_mut(CHANNELS) {
+            let level = frame.iter().fold(0.0, |max: f32, s| max.max(s.abs()));
+            let level_db = gain_to_db(level.max(1e-6));
+            let coeff = if level_db > self.envelope_db { self.attack } else { self.release };
+            self.envelope_db = level_db + (self.envelope_db - level_db) * coeff;
+            let over = (self.envelope_db - self.threshold_db).max(0.0);
+            let gain = db_to_gain(self.makeup_db - over * (1.0 - 1.0 / self.ratio));
+            frame.iter_mut().for_each(|s| *s *= gain);
+        }
+    }
+}
+
+// One-pole coefficient that gets about two thirds of the way there in the given time
+pub fn smoothing(ms: f32) -> f32 {
+    let samples = ms.max(0.0) / 1000.0 * sample_rate().get() as f32;
+    if samples < 1.0 {
+        return 0.0;
+    }
+    return (-1.0 / samples).exp();
+}
+
+// An old AM set: mono, only the middle of the spectrum, a little overdriven, with hiss and crackle
+// if there's any static
+pub struct Radio {
+    filters: [Biquad; 4],
+    noise_filters: [Biquad; 2],
+    hiss: f32,
+    crackle: f32, // Pops per second
+    drive: f32,
+}
+
+impl Radio {
+
+    pub fn new(low_hz: f64, high_hz: f64, hiss: f32, crackle: f32) -> Self {
+        return Radio {
+            filters: [
+                Biquad::high_pass(low_hz, 0.707), Biquad::high_pass(low_hz, 0.707),
+                Biquad::low_pass(high_hz, 0.707), Biquad::low_pass(high_hz, 0.707)
+            ],
+            noise_filters: [Biquad::high_pass(low_hz, 0.707), Biquad::low_pass(high_hz * 1.5, 0.707)],
+            hiss: hiss,
+            crackle: crackle,
+            drive: 1.5,
+        };
+    }
+}
+
+impl Effect for Radio {
+    fn process(&mut self, buf: &mut [Samp]) {
+        let pop_chance = self.crackle / sample_rate().get() as f32;
+        for frame in buf.chunks_exact_mut(CHANNELS) {
+            let mut s = frame.iter().sum::<Samp>() as f64 / CHANNELS as f64;
+            for filter in self.filters.iter_mut() {
+                s = filter.process(s);
+            }
+
+            let mut noise = (fastrand::f32() * 2.0 - 1.0) * self.hiss;
+            if fastrand::f32() < pop_chance {
+                noise += (fastrand::f32() * 2.0 - 1.0) * 0.3;
+            }
+            let mut noise = noise as f64;
+            for filter in self.noise_filters.iter_mut() {
+                noise = filter.process(noise);
+            }
+
+            let out = ((s as f32 * self.drive).tanh() / self.drive.tanh()) + noise as f32;
+            frame.fill(out);
+        }
+    }
+}
+
+// Effects for each bus, and the whole mix
+pub struct BusEffects {
+    pub voice: Chain,
+    pub sfx: Chain,
+    pub crowd: Chain,
+    pub music: Chain,
+    pub master: Chain,
+}
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum Preset {
+    Clean, // Just keeps the announcers even
+    Stadium, // Bat cracks and crowd echo round the ground
+    OldTimeRadio, // Crackly AM broadcast from a smaller park
+}
+
+// Hiss level and crackles per second at a radio static level of 1
+const RADIO_HISS: f32 = 0.01;
+const RADIO_CRACKLE: f32 = 3.0;
+
+impl Preset {
+    // Radio static only applies to the old-time radio, and 0 turns it off
+    pub fn effects(self, radio_static: f32) -> BusEffects {
+        let voice = Chain::new().with(Compressor::new(-20.0, 3.0, 5.0, 120.0, 3.0));
+        return match self {
+            Preset::Clean => BusEffects {
+                voice: voice,
+                sfx: Chain::new(),
+                crowd: Chain::new(),
+                music: Chain::new(),
+                master: Chain::new(),
+            },
+            Preset::Stadium => BusEffects {
+                voice: voice,
+                sfx: Chain::new().with(Reverb::new(0.85, 0.3, 0.5, 1.0)),
+                crowd: Chain::new().with(Reverb::new(0.9, 0.5, 0.35, 1.0)),
+                music: Chain::new().with(Reverb::new(0.8, 0.4, 0.25, 1.0)),
+                master: Chain::new(),
+            },
+            Preset::OldTimeRadio => BusEffects {
+                voice: Chain::new().with(Compressor::new(-24.0, 6.0, 2.0, 80.0, 6.0)),
+                sfx: Chain::new().with(Reverb::new(0.6, 0.5, 0.3, 1.0)),
+                crowd: Chain::new().with(Reverb::new(0.6, 0.6, 0.25, 1.0)),
+                music: Chain::new(),
+                master: Chain::new().with(Radio::new(300.0, 3000.0, RADIO_HISS * radio_static, RADIO_CRACKLE * radio_static)),
+            },
+        };
+    }
+}
@@ -5,21 +5,54 @@ pub struct Team {
     pub short_name: String
 }
 
+// What's known about a game before it's played out, for tagging the broadcast
+#[derive(Debug, Clone)]
+pub struct GameInfo {
+    pub title: String,
+    pub game_id: Option<String>,
+    pub date: Option<String>, // YYYY-MM-DD
+    pub season: Option<u32>,
+    pub day: Option<u32>,
+}
+
 #[derive(Debug)]
 pub struct Inning {
     pub was_top: bool,
     pub number: i32
 }
 
+// How hard the ball was hit, weakest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Hit {
+    Foul,
+    WeakGrounder,
+    LineDrive,
+    DeepFly,
+    HomeRun
+}
+
+impl Hit {
+    // Also the tag for samples meant for it
+    pub fn name(self) -> &'static str {
+        return match self {
+            Hit::Foul => "foul",
+            Hit::WeakGrounder => "weak_grounder",
+            Hit::LineDrive => "line_drive",
+            Hit::DeepFly => "deep_fly",
+            Hit::HomeRun => "home_run"
+        };
+    }
+}
+
 #[derive(Debug)]
 pub struct PlayEvent {
     // Message for the announcer to read
     pub message: String,
-    // Whether and how loud to play a baseball hit sound before the message
-    pub thwack: f32,
-    // How loud happy crowd cheering should be, 0-1
+    // Whether to play a baseball hit sound before the message, and for what sort of hit
+    pub thwack: Option<Hit>,
+    // How loud the batting team's fans cheer the play, 0-1
     pub yay: f32,
-    // How loud disappointed crowd cheering should be, 0-1
+    // How loud they groan at it, 0-1
     pub oh: f32,
     // Optional: Update home/away scores
     pub home_score: Option<i32>,
@@ -35,6 +68,7 @@ pub enum GameEvent {
     PlayEvent(PlayEvent),
     InningEnd(Inning),
     GameEnd(),
+    Clock(f64), // Seconds since the start of the game
     EndBroadcast()
 }
 
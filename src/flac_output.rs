@@ -0,0 +1,475 @@
+use crate::chapters::chapter_comments;
+use crate::output::{Destination, Marker, OutputError, Sink, comment_header, to_i16};
+use crate::types::{Samp, CHANNELS, sample_rate};
+
+// A small FLAC encoder: 16-bit, fixed blocks, the fixed polynomial predictors and Rice coded
+// residuals, picking whichever stereo decorrelation comes out smallest. Not as tight as libFLAC
+// at its best, but lossless and quick.
+
+const BLOCK_SIZE: usize = 4096; // Frames
+const BITS_PER_SAMPLE: u32 = 16;
+const MAX_FIXED_ORDER: usize = 4;
+const MAX_PARTITION_ORDER: u32 = 6;
+const MAX_RICE_PARAMETER: u32 = 14; // Highest the 4-bit parameter can be without an escape
+const STREAMINFO_OFFSET: u64 = 8; // After "fLaC" and the metadata block header
+const STREAMINFO_SIZE: usize = 34;
+const COMMENTS_OFFSET: u64 = STREAMINFO_OFFSET + STREAMINFO_SIZE as u64;
+// Room left after the comments for chapters, which we only know at the end
+const CHAPTER_SPACE: usize = 4096;
+
+// Ways of coding two channels, with the channel assignment number from the spec
+#[derive(Clone, Copy)]
+enum Stereo {
+    Independent = 1,
+    LeftSide = 8,
+    SideRight = 9,
+    MidSide = 10,
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    bits: u32, // Bits waiting in acc
+}
+
+impl BitWriter {
+
+    fn new() -> Self {
+        return BitWriter { bytes: Vec::new(), acc: 0, bits: 0 };
+    }
+
+    // Write the low count bits of value, most significant first
+    fn write(&mut self, value: u64, count: u32) {
+        if count == 0 {
+            return;
+        }
+        let mask = if count == 64 { u64::MAX } else { (1 << count) - 1 };
+        let mut count = count;
+        let value = value & mask;
+        // Keep acc from overflowing by emitting whole bytes as we go
+        while count > 0 {
+            let take = count.min(32);
+            count -= take;
+            self.acc = (self.acc << take) | ((value >> count) & ((1 << take) - 1));
+            self.bits += take;
+            while self.bits >= 8 {
+                self.bits -= 8;
+                self.bytes.push((self.acc >> self.bits) as u8);
+            }
+        }
+    }
+
+    fn write_signed(&mut self, value: i64, count: u32) {
+        self.write(value as u64, count);
+    }
+
+    fn write_unary(&mut self, zeros: u64) {
+        let mut zeros = zeros;
+        while zeros >= 32 {
+            self.write(0, 32);
+            zeros -= 32;
+        }
+        self.write(1, zeros as u32 + 1);
+    }
+
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+    }
+}
+
+pub struct FlacSink {
+    destination: Box<dyn Destination>,
+    pending: Vec<[i64; CHANNELS]>, // Frames waiting to fill a block
+    frame_number: u64,
+    total_frames: u64,
+    frame_sizes: Option<(usize, usize)>, // Smallest and largest encoded frames, in bytes
+    comments: Vec<(String, String)>,
+    chapters: Vec<(u64, String)>,
+}
+
+impl FlacSink {
+
+    pub fn new(mut destination: Box<dyn Destination>, comments: &[(String, String)]) -> Result<Self, OutputError> {
+        let mut header = Vec::new();
+        header.extend(b"fLaC");
+        header.push(0x00); // Type 0, STREAMINFO
+        header.extend(&(STREAMINFO_SIZE as u32).to_be_bytes()[1..]);
+        header.extend(streaminfo(0, None));
+        header.extend(comment_blocks(comments, CHAPTER_SPACE));
+        destination.write_all(&header)?;
+        return Ok(FlacSink {
+            destination: destination,
+            pending: Vec::with_capacity(BLOCK_SIZE),
+            frame_number: 0,
+            total_frames: 0,
+            frame_sizes: None,
+            comments: comments.to_vec(),
+            chapters: Vec::new(),
+        });
+    }
+
+    fn encode_block(&mut self) -> Result<(), OutputError> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let left: Vec<i64> = self.pending.iter().map(|f| f[0]).collect();
+        let right: Vec<i64> = self.pending.iter().map(|f| f[1]).collect();
+        let side: Vec<i64> = left.iter().zip(&right).map(|(l, r)| l - r).collect();
+        let mid: Vec<i64> = left.iter().zip(&right).map(|(l, r)| (l + r) >> 1).collect();
+
+        let bits = BITS_PER_SAMPLE;
+        let subframes = [
+            Subframe::best(&left, bits),
+            Subframe::best(&right, bits),
+            Subframe::best(&side, bits + 1),
+            Subframe::best(&mid, bits),
+        ];
+        let [l, r, s, m] = &subframes;
+        let (stereo, first, second) = [
+            (Stereo::Independent, l, r),
+            (Stereo::LeftSide, l, s),
+            (Stereo::SideRight, s, r),
+            (Stereo::MidSide, m, s),
+        ].into_iter().min_by_key(|(_, a, b)| a.cost + b.cost).unwrap();
+
+        let mut out = BitWriter::new();
+        out.write(0xfff8, 16); // Sync code, fixed block size
+        out.write(0b0111, 4); // Block size is in a 16-bit field after the header
+        out.write(0b0000, 4); // Sample rate as in STREAMINFO
+        out.write(stereo as u64, 4);
+        out.write(0b100, 3); // 16 bits per sample
+        out.write(0, 1);
+        write_utf8_number(&mut out, self.frame_number);
+        out.write(self.pending.len() as u64 - 1, 16);
+        let crc = crc8(&out.bytes);
+        out.write(crc as u64, 8);
+
+        first.write(&mut out);
+        second.write(&mut out);
+        out.align();
+        let crc = crc16(&out.bytes);
+        out.write(crc as u64, 16);
+
+        self.destination.write_all(&out.bytes)?;
+        let size = out.bytes.len();
+        self.frame_sizes = Some(match self.frame_sizes {
+            Some((min, max)) => (min.min(size), max.max(size)),
+            None => (size, size)
+        });
+        self.frame_number += 1;
+        self.total_frames += self.pending.len() as u64;
+        self.pending.clear();
+        return Ok(());
+    }
+}
+
+impl Sink for FlacSink {
+    fn write(&mut self, buf: &[Samp]) -> Result<(), OutputError> {
+        for frame in buf.chunks_exact(CHANNELS) {
+            self.pending.push([to_i16(frame[0]) as i64, to_i16(frame[1]) as i64]);
+            if self.pending.len() == BLOCK_SIZE {
+                self.encode_block()?;
+            }
+        }
+        return Ok(());
+    }
+
+    fn mark(&mut self, frame: u64, marker: &Marker) -> Result<(), OutputError> {
+        if let Marker::Chapter(title) = marker {
+            self.chapters.push((frame, title.clone()));
+        }
+        return Ok(());
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), OutputError> {
+        self.encode_block()?;
+        let info = streaminfo(self.total_frames, self.frame_sizes);
+        self.destination.rewrite(STREAMINFO_OFFSET, &info)?;
+
+        // Chapters go in as comments, in the space saved for them
+        let mut comments = self.comments.clone();
+        for (i, (frame, title)) in self.chapters.iter().enumerate() {
+            comments.extend(chapter_comments(i + 1, *frame, title));
+        }
+        let old_size = comment_header(&self.comments).len();
+        let new_size = comment_header(&comments).len();
+        if new_size <= old_size + CHAPTER_SPACE {
+            let blocks = comment_blocks(&comments, old_size + CHAPTER_SPACE - new_size);
+            self.destination.rewrite(COMMENTS_OFFSET, &blocks)?;
+        } else {
+            eprintln!("Too many chapters to fit in the FLAC file; leaving them out");
+        }
+        self.destination.flush()?;
+        return Ok(());
+    }
+}
+
+// The comment block, then the last metadata block: padding to leave room for more comments
+fn comment_blocks(comments: &[(String, String)], padding: usize) -> Vec<u8> {
+    let tags = comment_header(comments);
+    let mut out = Vec::new();
+    out.push(0x04); // Type 4, VORBIS_COMMENT
+    out.extend(&(tags.len() as u32).to_be_bytes()[1..]);
+    out.extend(tags);
+    out.push(0x81); // Last metadata block, type 1 (PADDING)
+    out.extend(&(padding as u32).to_be_bytes()[1..]);
+    out.resize(out.len() + padding, 0);
+    return out;
+}
+
+// Stream details. Zero lengths and sizes mean "unknown", which is all a pipe can say.
+fn streaminfo(total_frames: u64, frame_sizes: Option<(usize, usize)>) -> Vec<u8> {
+    let (min_frame, max_frame) = frame_sizes.unwrap_or((0, 0));
+    let mut out = BitWriter::new();
+    out.write(BLOCK_SIZE as u64, 16);
+    out.write(BLOCK_SIZE as u64, 16);
+    out.write(min_frame as u64, 24);
+    out.write(max_frame as u64, 24);
+    out.write(sample_rate().get() as u64, 20);
+    out.write(CHANNELS as u64 - 1, 3);
+    out.write(BITS_PER_SAMPLE as u64 - 1, 5);
+    out.write(total_frames, 36);
+    out.write(0, 64); // No MD5 signature
+    out.write(0, 64);
+    return out.bytes;
+}
+
+enum Kind {
+    Constant(i64),
+    Verbatim(Vec<i64>),
+    Fixed {
+        warmup: Vec<i64>,
+        partition_order: u32,
+        parameters: Vec<u32>,
+        residual: Vec<i64>,
+    },
+}
+
+struct Subframe {
+    bits: u32, // Bits per sample, one more for a side channel
+    kind: Kind,
+    cost: usize, // Size in bits
+}
+
+impl Subframe {
+
+    // Whichever coding of the channel is smallest
+    fn best(samples: &[i64], bits: u32) -> Subframe {
+        if samples.iter().all(|s| *s == samples[0]) {
+            return Subframe { bits: bits, kind: Kind::Constant(samples[0]), cost: 8 + bits as usize };
+        }
+        let mut best = Subframe {
+            bits: bits,
+            kind: Kind::Verbatim(samples.to_vec()),
+            cost: 8 + bits as usize * samples.len(),
+        };
+        for order in 0..=MAX_FIXED_ORDER.min(samples.len() - 1) {
+            let residual = fixed_residual(samples, order);
+            let (partition_order, parameters, residual_cost) = rice_partitions(&residual, samples.len(), order);
+            let cost = 8 + bits as usize * order + residual_cost;
+            if cost < best.cost {
+                best = Subframe {
+                    bits: bits,
+                    kind: Kind::Fixed {
+                        warmup: samples[..order].to_vec(),
+                        partition_order: partition_order,
+                        parameters: parameters,
+                        residual: residual,
+                    },
+                    cost: cost,
+                };
+            }
+        }
+        return best;
+    }
+
+    fn write(&self, out: &mut BitWriter) {
+        match &self.kind {
+            Kind::Constant(value) => {
+                out.write(0b0000000, 8);
+                out.write_signed(*value, self.bits);
+            },
+            Kind::Verbatim(samples) => {
+                out.write(0b0000010, 8);
+                samples.iter().for_each(|s| out.write_signed(*s, self.bits));
+            },
+            Kind::Fixed { warmup, partition_order, parameters, residual } => {
+                out.write(0b00010000 | (warmup.len() as u64) << 1, 8);
+                warmup.iter().for_each(|s| out.write_signed(*s, self.bits));
+                out.write(0b00, 2); // Rice coding with 4-bit parameters
+                out.write(*partition_order as u64, 4);
+                let block_size = residual.len() + warmup.len();
+                let mut start = 0;
+                for (p, parameter) in parameters.iter().enumerate() {
+                    let end = partition_end(p, block_size, *partition_order, warmup.len());
+                    out.write(*parameter as u64, 4);
+                    for r in &residual[start..end] {
+                        let folded = zigzag(*r);
+                        out.write_unary(folded >> parameter);
+                        out.write(folded, *parameter);
+                    }
+                    start = end;
+                }
+            }
+        }
+    }
+}
+
+// What's left after predicting each sample from the ones before it with a polynomial of the order
+fn fixed_residual(samples: &[i64], order: usize) -> Vec<i64> {
+    return (order..samples.len()).map(|i| {
+        let s = |back: usize| samples[i - back];
+        let prediction = match order {
+            0 => 0,
+            1 => s(1),
+            2 => 2 * s(1) - s(2),
+            3 => 3 * s(1) - 3 * s(2) + s(3),
+            _ => 4 * s(1) - 6 * s(2) + 4 * s(3) - s(4)
+        };
+        s(0) - prediction
+    }).collect();
+}
+
+// Index into the residual just past partition p. The first partition is short by the warmup.
+fn partition_end(p: usize, block_size: usize, partition_order: u32, order: usize) -> usize {
+    return ((p + 1) * (block_size >> partition_order)).min(block_size) - order;
+}
+
+// Best partition order and Rice parameters for a residual, and its size in bits
+fn rice_partitions(residual: &[i64], block_size: usize, order: usize) -> (u32, Vec<u32>, usize) {
+    let folded: Vec<u64> = residual.iter().map(|r| zigzag(*r)).collect();
+    let mut best: Option<(u32, Vec<u32>, usize)> = None;
+    for partition_order in 0..=MAX_PARTITION_ORDER {
+        let partitions = 1 << partition_order;
+        if block_size % partitions != 0 || block_size / partitions <= order {
+            break;
+        }
+        let mut parameters = Vec::with_capacity(partitions);
+        let mut cost = 6;
+        let mut start = 0;
+        for p in 0..partitions {
+            let end = partition_end(p, block_size, partition_order, order);
+            let (parameter, bits) = rice_parameter(&folded[start..end]);
+            parameters.push(parameter);
+            cost += 4 + bits;
+            start = end;
+        }
+        if best.as_ref().map_or(true, |(_, _, c)| cost < *c) {
+            best = Some((partition_order, parameters, cost));
+        }
+    }
+    return best.unwrap();
+}
+
+// Rice parameter for a partition, and the bits it takes, trying either side of the estimate
+fn rice_parameter(folded: &[u64]) -> (u32, usize) {
+    let sum: u64 = folded.iter().sum();
+    let mean = sum / folded.len().max(1) as u64;
+    let estimate = (64 - mean.leading_zeros()).min(MAX_RICE_PARAMETER);
+    return (estimate.saturating_sub(1)..=(estimate + 1).min(MAX_RICE_PARAMETER))
+        .map(|k| (k, folded.iter().map(|u| (u >> k) as usize + 1 + k as usize).sum()))
+        .min_by_key(|(_, bits)| *bits)
+        .unwrap();
+}
+
+// Signed to unsigned: 0, -1, 1, -2, 2... become 0, 1, 2, 3, 4...
+fn zigzag(r: i64) -> u64 {
+    return ((r << 1) ^ (r >> 63)) as u64;
+}
+
+// Frame numbers are written like UTF-8 characters
+fn write_utf8_number(out: &mut BitWriter, n: u64) {
+    if n < 0x80 {
+        out.write(n, 8);
+        return;
+    }
+    let mut continuation = 1;
+    while n >> (6 * continuation + 6 - continuation) != 0 {
+        continuation += 1;
+    }
+    let lead_marker = (0xff00u64 >> (continuation + 1)) & 0xff;
+    out.write(lead_marker | (n >> (6 * continuation)), 8);
+    for i in (0..continuation).rev() {
+        out.write(0x80 | ((n >> (6 * i)) & 0x3f), 8);
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc: u8 = 0;
+    for b in bytes {
+        crc ^= b;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+    }
+    return crc;
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for b in bytes {
+        crc ^= (*b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+        }
+    }
+    return crc;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::output::tests::{Memory, test_signal};
+
+    #[test]
+    fn decodes_to_what_went_in() {
+        let frames = BLOCK_SIZE * 2 + 1000;
+        let signal = test_signal(frames);
+        let memory = Memory::default();
+        let comments = vec![("TITLE".to_string(), "Test".to_string())];
+        let mut sink = Box::new(FlacSink::new(Box::new(memory.clone()), &comments).unwrap());
+        // In uneven pieces, so blocks get filled across writes
+        for piece in signal.chunks(777 * CHANNELS) {
+            sink.write(piece).unwrap();
+        }
+        sink.mark(4410, &Marker::Chapter("Middle".to_string())).unwrap();
+        sink.finish().unwrap();
+
+        // Frame and header CRCs are checked as it's read
+        let mut reader = claxon::FlacReader::new(Cursor::new(memory.bytes())).unwrap();
+        let info = reader.streaminfo();
+        assert_eq!(info.sample_rate, sample_rate().get());
+        assert_eq!(info.channels, CHANNELS as u32);
+        assert_eq!(info.bits_per_sample, BITS_PER_SAMPLE);
+        assert_eq!(info.samples, Some(frames as u64));
+        assert_eq!((info.min_block_size, info.max_block_size), (BLOCK_SIZE as u16, BLOCK_SIZE as u16));
+        assert!(info.min_frame_size.unwrap() > 0 && info.min_frame_size <= info.max_frame_size);
+        assert_eq!(reader.get_tag("TITLE").collect::<Vec<_>>(), ["Test"]);
+        assert_eq!(reader.get_tag("CHAPTER001NAME").collect::<Vec<_>>(), ["Middle"]);
+
+        let decoded: Vec<i32> = reader.samples().map(|s| s.unwrap()).collect();
+        let expected: Vec<i32> = signal.iter().map(|s| to_i16(*s) as i32).collect();
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn checksums_match_the_spec() {
+        // The standard check value for each CRC is over the ASCII digits 1 to 9
+        assert_eq!(crc8(b"123456789"), 0xf4);
+        assert_eq!(crc16(b"123456789"), 0xfee8);
+    }
+
+    #[test]
+    fn frame_numbers_are_coded_like_utf8() {
+        for (n, expected) in [(0x7f, vec![0x7f]), (0x80, vec![0xc2, 0x80]), (0x800, vec![0xe0, 0xa0, 0x80]),
+            (0x10000, vec![0xf0, 0x90, 0x80, 0x80])] {
+            let mut out = BitWriter::new();
+            write_utf8_number(&mut out, n);
+            assert_eq!(out.bytes, expected, "{:#x}", n);
+        }
+    }
+}
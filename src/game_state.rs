@@ -1,8 +1,18 @@
 use std::sync::mpsc::{Sender, Receiver};
 use std::thread::{JoinHandle, self};
 
-use crate::announce_channel::AnnounceEvent;
-use crate::events::{Team, GameEvent, PlayEvent, Inning};
+use crate::announce_channel::{AnnounceEvent, Voice, Pace, Stands, MusicCue};
+use crate::colour;
+use crate::events::{Team, GameEvent, PlayEvent, Inning, Hit};
+use crate::types::{self, sample_rate, ms_to_samples};
+
+// How loud the away fans are next to the home crowd
+const AWAY_FANS: f32 = 0.6;
+// How much the crowd cheers or groans at each run
+const RUN_REACTION: f32 = 0.6;
+// Music plays on its own for a bit before the announcer comes in
+const MUSIC_LEAD_IN_MS: u64 = 2000;
+const STRETCH_INNING: i32 = 7;
 
 pub struct GameState {
 
@@ -12,7 +22,11 @@ pub struct GameState {
     away_team: Team,
 
     home_score: i32,
-    away_score: i32
+    away_score: i32,
+    home_batting: bool,
+
+    // Plays since anything interesting happened
+    quiet_plays: u32
 }
 
 impl GameState {
@@ -30,76 +44,176 @@ impl GameState {
             },
 
             home_score: 0,
-            away_score: 0
+            away_score: 0,
+            home_batting: false,
+            quiet_plays: 0
         }
     }
 
     fn pregame(mut self, home: Team, away: Team) -> GameState {
         self.home_team = home;
         self.away_team = away;
-        // Announce upcoming game
-        let message = format!("This is radio blaseball one thirteen point four. Next up: {} vs {}.", self.home_team.full_name, self.away_team.full_name);
-        self.announce.send(AnnounceEvent::Message(message)).unwrap();
+        let matchup = format!("{} vs {}", self.home_team.short_name, self.away_team.short_name);
+        self.announce.send(AnnounceEvent::NowPlaying(matchup)).unwrap();
+        self.announce.send(AnnounceEvent::Chapter("Pregame".to_string())).unwrap();
+        // Announce upcoming game, over the theme
+        self.announce.send(AnnounceEvent::MusicStart(MusicCue::Intro)).unwrap();
+        self.announce.send(AnnounceEvent::Delay(ms_to_samples(MUSIC_LEAD_IN_MS))).unwrap();
+        let message = format!("This is radio blaseball one thirteen point four. <break time=\"400ms\"/> Next up: {} <emphasis>vs</emphasis> {}.", self.home_team.full_name, self.away_team.full_name);
+        self.announce.send(AnnounceEvent::Message(Voice::PlayByPlay, message, Pace::Measured)).unwrap();
+        self.announce.send(AnnounceEvent::Beat()).unwrap();
+        self.announce.send(AnnounceEvent::MusicStop()).unwrap();
         return self;
     }
 
     fn play_ball(self) -> GameState  {
+        self.announce.send(AnnounceEvent::Chapter(half_inning(true, 1))).unwrap();
         let message = format!("Play ball!");
-        self.announce.send(AnnounceEvent::Message(message)).unwrap();
+        self.announce.send(AnnounceEvent::Message(Voice::PlayByPlay, message, Pace::Excited)).unwrap();
         return self;
     }
 
     fn play_event(mut self, play_event: PlayEvent) -> GameState  {
+        let mut scoring_team = None;
+        let mut home_runs = 0;
+        let mut away_runs = 0;
         match play_event.home_score {
-            Some(score) => self.home_score = score,
+            Some(score) => {
+                if score > self.home_score {
+                    scoring_team = Some(&self.home_team);
+                    home_runs = score - self.home_score;
+                }
+                self.home_score = score
+            },
             None => ()
         }
 
         match play_event.away_score {
-            Some(score) => self.away_score = score,
+            Some(score) => {
+                if score > self.away_score {
+                    scoring_team = Some(&self.away_team);
+                    away_runs = score - self.away_score;
+                }
+                self.away_score = score
+            },
             None => ()
         }
 
-        if play_event.thwack > 0.0 {
-            self.announce.send(AnnounceEvent::Thwack(play_event.thwack)).unwrap();
+        // The play as the home crowd sees it: hits and outs count for whoever's batting, and runs
+        // for whoever scored
+        let (yay, oh) = if self.home_batting { (play_event.yay, play_event.oh) } else { (play_event.oh, play_event.yay) };
+        let yay = (yay + RUN_REACTION * home_runs as f32).min(1.0);
+        let oh = (oh + RUN_REACTION * away_runs as f32).min(1.0);
+
+        let big_play = scoring_team.is_some() || play_event.thwack.map_or(false, |hit| hit > Hit::Foul);
+        let colour_line = match scoring_team {
+            Some(team) => Some((colour::big_play(team), Pace::Excited)),
+            None if self.quiet_plays >= colour::LULL_LENGTH => Some((colour::lull(self.trailing_team()), Pace::Normal)),
+            None => None
+        };
+
+        if let Some(hit) = play_event.thwack {
+            self.announce.send(AnnounceEvent::Thwack(hit)).unwrap();
+        }
+        // The home crowd cheers their team on and groans at the other; the travelling fans do
+        // the cheering when it goes the other way
+        if yay > 0.0 {
+            self.announce.send(AnnounceEvent::Cheer(yay, Stands::Home)).unwrap();
+        }
+        if oh > 0.0 {
+            self.announce.send(AnnounceEvent::Groan(oh, Stands::Home)).unwrap();
+            self.announce.send(AnnounceEvent::Cheer(oh * AWAY_FANS, Stands::Away)).unwrap();
         }
 
-        self.announce.send(AnnounceEvent::Message(play_event.message)).unwrap();
+        if scoring_team.is_some() {
+            self.announce.send(AnnounceEvent::NowPlaying(self.score())).unwrap();
+        }
+        let pace = if big_play { Pace::Excited } else { Pace::Normal };
+        self.announce.send(AnnounceEvent::Message(Voice::PlayByPlay, play_event.message, pace)).unwrap();
         self.announce.send(AnnounceEvent::Beat()).unwrap();
 
         match play_event.outs {
-            Some(1) => self.announce.send(AnnounceEvent::Message(format!("First out."))),
-            Some(2) => self.announce.send(AnnounceEvent::Message(format!("Second out."))),
+            Some(1) => self.announce.send(AnnounceEvent::Message(Voice::PlayByPlay, format!("First out."), Pace::Normal)),
+            Some(2) => self.announce.send(AnnounceEvent::Message(Voice::PlayByPlay, format!("Second out."), Pace::Normal)),
             _ => Ok(()),
         }.unwrap();
 
+        match colour_line {
+            Some((line, pace)) => {
+                self.announce.send(AnnounceEvent::Message(Voice::Colour, line, pace)).unwrap();
+                self.announce.send(AnnounceEvent::Beat()).unwrap();
+                self.quiet_plays = 0;
+            },
+            None => self.quiet_plays += 1
+        }
+
         return self;
     }
 
-    fn inning_end(self, inning: Inning) -> GameState  {
+    fn inning_end(mut self, inning: Inning) -> GameState  {
+        // The away team bats in the top of each inning
+        self.home_batting = inning.was_top;
+        // A new chapter for the half that's coming up
+        let chapter = if inning.was_top {
+            half_inning(false, inning.number)
+        } else {
+            half_inning(true, inning.number + 1)
+        };
+        self.announce.send(AnnounceEvent::Chapter(chapter)).unwrap();
+        if inning.was_top && inning.number == STRETCH_INNING {
+            self.announce.send(AnnounceEvent::MusicStart(MusicCue::Stretch)).unwrap();
+        }
         let message = format!("End of the {0:?} of the {1}. {2} {3}, {4} {5}.",
             if inning.was_top { "top" } else { "bottom" }, inning.number,
             self.home_team.short_name, self.home_score,
             self.away_team.short_name, self.away_score);
-        self.announce.send(AnnounceEvent::Message(message)).unwrap();
+        self.announce.send(AnnounceEvent::Message(Voice::PlayByPlay, message, Pace::Normal)).unwrap();
+        self.announce.send(AnnounceEvent::Beat()).unwrap();
+        let batted = if inning.was_top { &self.away_team } else { &self.home_team };
+        self.announce.send(AnnounceEvent::Message(Voice::Colour, colour::inning_break(batted), Pace::Normal)).unwrap();
         self.announce.send(AnnounceEvent::Beat()).unwrap();
         let message2 = format!("{} is at bat.", 
             if inning.was_top { &self.home_team.short_name } else { &self.away_team.short_name }
         );
-        self.announce.send(AnnounceEvent::Message(message2)).unwrap();
+        self.announce.send(AnnounceEvent::Message(Voice::PlayByPlay, message2, Pace::Normal)).unwrap();
+        if inning.was_top && inning.number == STRETCH_INNING {
+            self.announce.send(AnnounceEvent::MusicStop()).unwrap();
+        }
         return self;
     }
 
     fn game_end(self) -> GameState  {
-        let message = format!("Game over. {} {}, {} {}.",
+        self.announce.send(AnnounceEvent::Chapter("Postgame".to_string())).unwrap();
+        self.announce.send(AnnounceEvent::MusicStart(MusicCue::Outro)).unwrap();
+        self.announce.send(AnnounceEvent::Delay(ms_to_samples(MUSIC_LEAD_IN_MS / 2))).unwrap();
+        let message = format!("Game over. <break time=\"300ms\"/> {} {}, {} {}.",
             self.home_team.full_name, self.home_score,
             self.away_team.full_name, self.away_score);
-        self.announce.send(AnnounceEvent::Message(message)).unwrap();
+        self.announce.send(AnnounceEvent::NowPlaying(format!("Final: {}", self.score()))).unwrap();
+        self.announce.send(AnnounceEvent::Message(Voice::PlayByPlay, message, Pace::Measured)).unwrap();
 
         return self;
     }
 
+    fn score(&self) -> String {
+        return format!("{} {}, {} {}",
+            self.home_team.short_name, self.home_score,
+            self.away_team.short_name, self.away_score);
+    }
+
+    fn clock(self, seconds: f64) -> GameState {
+        let samples = (seconds * sample_rate().get() as f64) as u64;
+        self.announce.send(AnnounceEvent::Clock(samples)).unwrap();
+        return self;
+    }
+
+    fn trailing_team(&self) -> &Team {
+        return if self.home_score < self.away_score { &self.home_team } else { &self.away_team };
+    }
+
     fn end_broadcast(self) -> GameState {
+        // Fade out the outro rather than let it stop dead
+        self.announce.send(AnnounceEvent::MusicStop()).unwrap();
         self.announce.send(AnnounceEvent::Finish()).unwrap();
         return self;
     }
@@ -107,8 +221,21 @@ impl GameState {
 
 }
 
-pub fn spawn_game_thread(rx: Receiver<GameEvent>, tx: Sender<AnnounceEvent>) -> JoinHandle<i32> {
+// Like "Top of the 7th"
+fn half_inning(top: bool, number: i32) -> String {
+    let suffix = match (number % 100, number % 10) {
+        (11..=13, _) => "th",
+        (_, 1) => "st",
+        (_, 2) => "nd",
+        (_, 3) => "rd",
+        _ => "th"
+    };
+    return format!("{} of the {}{}", if top { "Top" } else { "Bottom" }, number, suffix);
+}
+
+pub fn spawn_game_thread(rx: Receiver<GameEvent>, tx: Sender<AnnounceEvent>, seed: Option<u64>) -> JoinHandle<i32> {
     return thread::spawn(move || {
+        types::seed_thread(seed, types::GAME_STREAM);
         game_loop(rx, tx);
         return 0;
     });
@@ -124,6 +251,7 @@ fn game_loop(rx: Receiver<GameEvent>, tx: Sender<AnnounceEvent>) {
             GameEvent::PlayEvent(play_event) => game.play_event(play_event),
             GameEvent::InningEnd(inning) => game.inning_end(inning),
             GameEvent::GameEnd() => game.game_end(),
+            GameEvent::Clock(seconds) => game.clock(seconds),
             GameEvent::EndBroadcast() => { game.end_broadcast(); break; }
         }
     }
@@ -0,0 +1,360 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::opus_output::{OpusEncoder, OPUS_RATE, FRAME_SIZE};
+use crate::output::{OutputError, Settings, Sink};
+use crate::types::{Samp, CHANNELS, sample_rate};
+
+// HTTP Live Streaming into a directory, for browsers and anything else that can play HLS from a
+// static file server. Audio is Opus in fragmented MP4: an init.mp4 with the codec setup, then a
+// numbered .m4s segment every SEGMENT_SECONDS, with stream.m3u8 rewritten as each one is done.
+// While live, the playlist lists the last few segments (or all of them, with no window); once the
+// game ends it's rewritten to list everything, as video on demand. Players usually start three
+// segments back from the newest, so they run around three segments behind the broadcast.
+
+const SEGMENT_SECONDS: usize = 4;
+const PACKETS_PER_SEGMENT: usize = SEGMENT_SECONDS * OPUS_RATE as usize / FRAME_SIZE;
+const PLAYLIST: &str = "stream.m3u8";
+const INIT_SEGMENT: &str = "init.mp4";
+const TRACK_ID: u32 = 1;
+
+pub struct HlsSink {
+    directory: PathBuf,
+    window: Option<usize>, // Segments listed while live, or None for all of them
+    encoder: OpusEncoder,
+    packets: Vec<Vec<u8>>, // Waiting to go in the next segment
+    segments: Vec<f64>, // Length of each one written, in seconds
+    decode_time: u64, // Start of the next segment, at 48kHz
+}
+
+impl HlsSink {
+
+    pub fn new(directory: &Path, window: Option<usize>, settings: &Settings) -> Result<Self, OutputError> {
+        fs::create_dir_all(directory)?;
+        let encoder = OpusEncoder::new(settings.bitrate)?;
+        File::create(directory.join(INIT_SEGMENT))?.write_all(&init_segment(encoder.pre_skip))?;
+        let sink = HlsSink {
+            directory: directory.to_path_buf(),
+            window: window,
+            encoder: encoder,
+            packets: Vec::new(),
+            segments: Vec::new(),
+            decode_time: 0,
+        };
+        sink.write_playlist(false)?;
+        return Ok(sink);
+    }
+
+    fn segment_name(index: usize) -> String {
+        return format!("segment{:05}.m4s", index);
+    }
+
+    fn write_segment(&mut self, packets: &[Vec<u8>]) -> Result<(), OutputError> {
+        let name = HlsSink::segment_name(self.segments.len());
+        let segment = media_segment(self.segments.len() as u32 + 1, self.decode_time, packets);
+        File::create(self.directory.join(name))?.write_all(&segment)?;
+        let samples = (packets.len() * FRAME_SIZE) as u64;
+        self.decode_time += samples;
+        self.segments.push(samples as f64 / OPUS_RATE as f64);
+        return Ok(());
+    }
+
+    // Written to the side and renamed into place, so nobody ever reads half a playlist
+    fn write_playlist(&self, ended: bool) -> Result<(), OutputError> {
+        let first = match self.window {
+            Some(window) if !ended => self.segments.len().saturating_sub(window),
+            _ => 0
+        };
+        let mut text = format!("#EXTM3U\n#EXT-X-VERSION:7\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:{}\n",
+            SEGMENT_SECONDS, first);
+        if ended {
+            text += "#EXT-X-PLAYLIST-TYPE:VOD\n";
+        } else if self.window.is_none() {
+            text += "#EXT-X-PLAYLIST-TYPE:EVENT\n";
+        }
+        text += &format!("#EXT-X-INDEPENDENT-SEGMENTS\n#EXT-X-MAP:URI=\"{}\"\n", INIT_SEGMENT);
+        for (index, seconds) in self.segments.iter().enumerate().skip(first) {
+            text += &format!("#EXTINF:{:.3},\n{}\n", seconds, HlsSink::segment_name(index));
+        }
+        if ended {
+            text += "#EXT-X-ENDLIST\n";
+        }
+        let temporary = self.directory.join(format!("{}.tmp", PLAYLIST));
+        fs::write(&temporary, text)?;
+        fs::rename(&temporary, self.directory.join(PLAYLIST))?;
+        return Ok(());
+    }
+}
+
+impl Sink for HlsSink {
+    fn write(&mut self, buf: &[Samp]) -> Result<(), OutputError> {
+        let packets = self.encoder.encode(buf, false)?;
+        self.packets.extend(packets);
+        while self.packets.len() >= PACKETS_PER_SEGMENT {
+            let segment: Vec<Vec<u8>> = self.packets.drain(..PACKETS_PER_SEGMENT).collect();
+            self.write_segment(&segment)?;
+            self.write_playlist(false)?;
+        }
+        return Ok(());
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), OutputError> {
+        let packets = self.encoder.encode(&[], true)?;
+        self.packets.extend(packets);
+        let rest: Vec<Vec<u8>> = self.packets.drain(..).collect();
+        for segment in rest.chunks(PACKETS_PER_SEGMENT) {
+            self.write_segment(segment)?;
+        }
+        self.write_playlist(true)?;
+        return Ok(());
+    }
+}
+
+fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len() + 8);
+    out.extend((body.len() as u32 + 8).to_be_bytes());
+    out.extend(kind);
+    out.extend(body);
+    return out;
+}
+
+fn full_box(kind: &[u8; 4], version: u8, flags: u32, body: &[u8]) -> Vec<u8> {
+    let mut full = ((version as u32) << 24 | flags).to_be_bytes().to_vec();
+    full.extend(body);
+    return mp4_box(kind, &full);
+}
+
+const MATRIX: [u32; 9] = [0x10000, 0, 0, 0, 0x10000, 0, 0, 0, 0x40000000];
+
+// ftyp and moov: one audio track with no samples of its own, which the segments fill in
+fn init_segment(pre_skip: u64) -> Vec<u8> {
+    let mut ftyp = Vec::new();
+    ftyp.extend(b"iso6");
+    ftyp.extend(0u32.to_be_bytes());
+    ftyp.extend(b"iso6mp41");
+
+    let mut mvhd = vec![0; 8]; // Creation and modification times
+    mvhd.extend(OPUS_RATE.to_be_bytes());
+    mvhd.extend(0u32.to_be_bytes()); // Duration, unknown
+    mvhd.extend(0x10000u32.to_be_bytes()); // Rate
+    mvhd.extend(0x100u16.to_be_bytes()); // Volume
+    mvhd.extend([0; 10]);
+    MATRIX.iter().for_each(|m| mvhd.extend(m.to_be_bytes()));
+    mvhd.extend([0; 24]);
+    mvhd.extend((TRACK_ID + 1).to_be_bytes()); // Next track id
+
+    let mut tkhd = vec![0; 8];
+    tkhd.extend(TRACK_ID.to_be_bytes());
+    tkhd.extend([0; 4]);
+    tkhd.extend(0u32.to_be_bytes()); // Duration
+    tkhd.extend([0; 8]);
+    tkhd.extend(0u16.to_be_bytes()); // Layer
+    tkhd.extend(0u16.to_be_bytes()); // Alternate group
+    tkhd.extend(0x100u16.to_be_bytes()); // Volume
+    tkhd.extend([0; 2]);
+    MATRIX.iter().for_each(|m| tkhd.extend(m.to_be_bytes()));
+    tkhd.extend([0; 8]); // Width and height
+
+    // The edit list skips the encoder delay, like the pre-skip in Ogg Opus
+    let mut elst = 1u32.to_be_bytes().to_vec();
+    elst.extend(0u32.to_be_bytes()); // Duration, all of it
+    elst.extend((pre_skip as u32).to_be_bytes()); // Media time
+    elst.extend(0x10000u32.to_be_bytes()); // Rate
+
+    let mut mdhd = vec![0; 8];
+    mdhd.extend(OPUS_RATE.to_be_bytes());
+    mdhd.extend(0u32.to_be_bytes());
+    mdhd.extend(0x55c4u16.to_be_bytes()); // "und"
+    mdhd.extend([0; 2]);
+
+    let mut hdlr = vec![0; 4];
+    hdlr.extend(b"soun");
+    hdlr.extend([0; 12]);
+    hdlr.extend(b"bladio\0");
+
+    let mut url = full_box(b"url ", 0, 1, &[]); // Flag 1: the data's in this file
+    let mut dref = 1u32.to_be_bytes().to_vec();
+    dref.append(&mut url);
+
+    // Opus in ISO BMFF: an audio sample entry always at 48kHz, then the OpusHead fields big-endian
+    let mut dops = vec![0, CHANNELS as u8];
+    dops.extend((pre_skip as u16).to_be_bytes());
+    dops.extend(sample_rate().get().to_be_bytes());
+    dops.extend(0i16.to_be_bytes()); // Output gain
+    dops.push(0); // Mono or stereo, no mapping table
+    let mut entry = vec![0; 6];
+    entry.extend(1u16.to_be_bytes()); // Data reference index
+    entry.extend([0; 8]);
+    entry.extend((CHANNELS as u16).to_be_bytes());
+    entry.extend(16u16.to_be_bytes()); // Sample size
+    entry.extend([0; 4]);
+    entry.extend((OPUS_RATE << 16).to_be_bytes());
+    entry.extend(mp4_box(b"dOps", &dops));
+    let mut stsd = 1u32.to_be_bytes().to_vec();
+    stsd.extend(mp4_box(b"Opus", &entry));
+
+    let mut stsz = 0u32.to_be_bytes().to_vec();
+    stsz.extend(0u32.to_be_bytes());
+    let stbl = [
+        full_box(b"stsd", 0, 0, &stsd),
+        full_box(b"stts", 0, 0, &0u32.to_be_bytes()),
+        full_box(b"stsc", 0, 0, &0u32.to_be_bytes()),
+        full_box(b"stsz", 0, 0, &stsz),
+        full_box(b"stco", 0, 0, &0u32.to_be_bytes()),
+    ].concat();
+    let minf = [
+        full_box(b"smhd", 0, 0, &[0; 4]),
+        mp4_box(b"dinf", &full_box(b"dref", 0, 0, &dref)),
+        mp4_box(b"stbl", &stbl),
+    ].concat();
+    let mdia = [
+        full_box(b"mdhd", 0, 0, &mdhd),
+        full_box(b"hdlr", 0, 0, &hdlr),
+        mp4_box(b"minf", &minf),
+    ].concat();
+    let trak = [
+        full_box(b"tkhd", 0, 3, &tkhd), // Enabled and in the movie
+        mp4_box(b"edts", &full_box(b"elst", 0, 0, &elst)),
+        mp4_box(b"mdia", &mdia),
+    ].concat();
+
+    let mut trex = TRACK_ID.to_be_bytes().to_vec();
+    trex.extend(1u32.to_be_bytes()); // Sample description index
+    trex.extend((FRAME_SIZE as u32).to_be_bytes()); // Sample duration
+    trex.extend([0; 8]); // Sample size and flags
+    let moov = [
+        full_box(b"mvhd", 0, 0, &mvhd),
+        mp4_box(b"trak", &trak),
+        mp4_box(b"mvex", &full_box(b"trex", 0, 0, &trex)),
+    ].concat();
+
+    return [mp4_box(b"ftyp", &ftyp), mp4_box(b"moov", &moov)].concat();
+}
+
+// moof and mdat for a run of packets starting at decode_time
+fn media_segment(sequence: u32, decode_time: u64, packets: &[Vec<u8>]) -> Vec<u8> {
+    let moof = |data_offset: u32| {
+        let mut trun = (packets.len() as u32).to_be_bytes().to_vec();
+        trun.extend(data_offset.to_be_bytes());
+        for packet in packets {
+            trun.extend((FRAME_SIZE as u32).to_be_bytes());
+            trun.extend((packet.len() as u32).to_be_bytes());
+        }
+        let traf = [
+            full_box(b"tfhd", 0, 0x20000, &TRACK_ID.to_be_bytes()), // Offsets are from the moof
+            full_box(b"tfdt", 1, 0, &decode_time.to_be_bytes()),
+            full_box(b"trun", 0, 0x301, &trun), // Data offset, then each sample's duration and size
+        ].concat();
+        let moof = [
+            full_box(b"mfhd", 0, 0, &sequence.to_be_bytes()),
+            mp4_box(b"traf", &traf),
+        ].concat();
+        return mp4_box(b"moof", &moof);
+    };
+    // The data starts after the moof and the mdat header
+    let length = moof(0).len() as u32;
+    return [moof(length + 8), mp4_box(b"mdat", &packets.concat())].concat();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::tests::test_signal;
+
+    const CONTAINERS: [&[u8; 4]; 10] = [b"moov", b"trak", b"edts", b"mdia", b"minf", b"dinf", b"stbl", b"mvex", b"moof", b"traf"];
+
+    // Each box's type, where it starts and its body, checking the sizes fill the bytes exactly
+    fn boxes(bytes: &[u8]) -> Vec<([u8; 4], usize, &[u8])> {
+        let mut boxes = Vec::new();
+        let mut pos = 0;
+        while pos < bytes.len() {
+            let size = u32::from_be_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize;
+            assert!(size >= 8 && pos + size <= bytes.len(), "box at {} runs off the end", pos);
+            boxes.push((bytes[pos + 4..pos + 8].try_into().unwrap(), pos, &bytes[pos + 8..pos + size]));
+            pos += size;
+        }
+        assert_eq!(pos, bytes.len());
+        return boxes;
+    }
+
+    // Walks every container box, checking the sizes all the way down
+    fn walk(bytes: &[u8]) {
+        for (kind, _, body) in boxes(bytes) {
+            if CONTAINERS.contains(&&kind) {
+                walk(body);
+            }
+        }
+    }
+
+    fn child<'a>(bytes: &'a [u8], kind: &[u8; 4]) -> &'a [u8] {
+        return boxes(bytes).into_iter().find(|(k, _, _)| k == kind).map(|(_, _, body)| body)
+            .unwrap_or_else(|| panic!("no {:?} box", String::from_utf8_lossy(kind)));
+    }
+
+    fn be_u32(bytes: &[u8]) -> u32 {
+        return u32::from_be_bytes(bytes[..4].try_into().unwrap());
+    }
+
+    #[test]
+    fn segments_line_up_with_the_playlist() {
+        let directory = std::env::temp_dir().join(format!("bladio-hls-{}", std::process::id()));
+        let mut sink = Box::new(HlsSink::new(&directory, Some(2), &Settings::default()).unwrap());
+        let pre_skip = sink.encoder.pre_skip;
+        let signal = test_signal(sample_rate().get() as usize * (SEGMENT_SECONDS * 2 + 1));
+        for block in signal.chunks(1000 * CHANNELS) {
+            sink.write(block).unwrap();
+        }
+        sink.finish().unwrap();
+
+        let init = fs::read(directory.join(INIT_SEGMENT)).unwrap();
+        walk(&init);
+        let top: Vec<[u8; 4]> = boxes(&init).iter().map(|(kind, _, _)| *kind).collect();
+        assert_eq!(top, [*b"ftyp", *b"moov"]);
+        let stbl = [b"trak", b"mdia", b"minf", b"stbl"].iter().fold(child(&init, b"moov"), |body, kind| child(body, kind));
+        assert_eq!(&boxes(&child(stbl, b"stsd")[8..])[0].0, b"Opus");
+
+        let playlist = fs::read_to_string(directory.join(PLAYLIST)).unwrap();
+        assert!(playlist.contains("#EXT-X-PLAYLIST-TYPE:VOD\n"));
+        assert!(playlist.ends_with("#EXT-X-ENDLIST\n"));
+        assert!(playlist.contains("#EXT-X-MEDIA-SEQUENCE:0\n"), "the finished playlist lists everything");
+        let listed: Vec<&str> = playlist.lines().filter(|line| line.ends_with(".m4s")).collect();
+        let lengths: Vec<f64> = playlist.lines()
+            .filter_map(|line| line.strip_prefix("#EXTINF:"))
+            .map(|line| line.trim_end_matches(',').parse().unwrap())
+            .collect();
+        assert_eq!(listed.len(), 3);
+        assert_eq!(lengths.len(), 3);
+
+        let mut decode_time = 0;
+        for (index, name) in listed.iter().enumerate() {
+            assert_eq!(*name, HlsSink::segment_name(index));
+            let segment = fs::read(directory.join(name)).unwrap();
+            walk(&segment);
+            let top = boxes(&segment);
+            assert_eq!((top[0].0, top[1].0), (*b"moof", *b"mdat"));
+            let (moof, mdat_start, mdat) = (top[0].2, top[1].1, top[1].2);
+            assert_eq!(be_u32(&child(moof, b"mfhd")[4..]), index as u32 + 1);
+            let traf = child(moof, b"traf");
+
+            // Version 1, so a 64-bit time after the version and flags
+            let tfdt = child(traf, b"tfdt");
+            assert_eq!(u64::from_be_bytes(tfdt[4..12].try_into().unwrap()), decode_time);
+
+            // The data offset is from the start of the moof, which is the start of the file
+            let trun = child(traf, b"trun");
+            let count = be_u32(&trun[4..]) as usize;
+            assert_eq!(be_u32(&trun[8..]) as usize, mdat_start + 8);
+            let sizes: usize = (0..count).map(|i| be_u32(&trun[16 + i * 8..]) as usize).sum();
+            assert_eq!(sizes, mdat.len());
+            assert!((0..count).all(|i| be_u32(&trun[12 + i * 8..]) == FRAME_SIZE as u32));
+            decode_time += (count * FRAME_SIZE) as u64;
+            assert!((lengths[index] - (count * FRAME_SIZE) as f64 / OPUS_RATE as f64).abs() < 0.001);
+        }
+        // Everything that went in, and the encoder delay, with the last packet padded out
+        let resampled = signal.len() as u64 / CHANNELS as u64 * OPUS_RATE as u64 / sample_rate().get() as u64;
+        let end = pre_skip + resampled;
+        assert!(decode_time + 1 >= end && decode_time < end + FRAME_SIZE as u64, "{} for {}", decode_time, end);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,292 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{SyncSender, TrySendError, sync_channel};
+use std::thread::spawn;
+use std::time::Duration;
+
+use crate::output::{Destination, Marker, OutputError, Settings, Sink};
+use crate::types::Samp;
+use crate::vorbis_output::VorbisSink;
+
+// Serves the broadcast as Ogg Vorbis over HTTP on localhost, like a tiny Icecast. It's encoded
+// once; listeners who join late get the stream's header pages first and then carry on from the
+// next page, which is how Icecast does it too. Clients that ask for ICY metadata get the now
+// playing text every ICY_METAINT bytes, and /nowplaying has it as plain text.
+
+const ICY_METAINT: usize = 16000;
+// Pages a listener can fall behind before we give up on them, rather than hold up everyone else
+const MAX_BACKLOG_PAGES: usize = 2048;
+const MAX_REQUEST_BYTES: usize = 8192;
+// How long a listener gets to send its request, and to take each write, before we give up on them
+const READ_TIMEOUT_S: u64 = 10;
+const WRITE_TIMEOUT_S: u64 = 30;
+
+struct Station {
+    name: String,
+    headers: Vec<u8>, // Ogg header pages, sent to everyone before they join
+    listeners: Vec<SyncSender<Arc<Vec<u8>>>>,
+    now_playing: String,
+}
+
+pub struct HttpSink {
+    encoder: VorbisSink,
+    station: Arc<Mutex<Station>>,
+}
+
+impl HttpSink {
+
+    pub fn new(port: u16, name: &str, comments: &[(String, String)], settings: &Settings) -> Result<Self, OutputError> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        let station = Arc::new(Mutex::new(Station {
+            name: name.to_string(),
+            headers: Vec::new(),
+            listeners: Vec::new(),
+            now_playing: String::new(),
+        }));
+        let accepting = station.clone();
+        spawn(move || accept(listener, accepting));
+        eprintln!("Streaming on http://{}:{}/", Ipv4Addr::LOCALHOST, port);
+
+        let pages = Pages { station: station.clone(), pending: Vec::new(), in_headers: true };
+        return Ok(HttpSink {
+            encoder: VorbisSink::new(Box::new(pages), comments, settings.quality)?,
+            station: station,
+        });
+    }
+}
+
+impl Sink for HttpSink {
+    fn write(&mut self, buf: &[Samp]) -> Result<(), OutputError> {
+        return self.encoder.write(buf);
+    }
+
+    fn mark(&mut self, _frame: u64, marker: &Marker) -> Result<(), OutputError> {
+        if let Marker::NowPlaying(text) = marker {
+            self.station.lock().unwrap().now_playing = text.clone();
+        }
+        return Ok(());
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), OutputError> {
+        let HttpSink { encoder, station } = *self;
+        Box::new(encoder).finish()?;
+        // Hanging up on everyone ends their streams
+        station.lock().unwrap().listeners.clear();
+        return Ok(());
+    }
+}
+
+// Takes the encoder's output and splits it into Ogg pages for the listeners
+struct Pages {
+    station: Arc<Mutex<Station>>,
+    pending: Vec<u8>,
+    in_headers: bool, // Still on the pages before the first audio
+}
+
+impl Write for Pages {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        while let Some(length) = page_length(&self.pending) {
+            let page: Vec<u8> = self.pending.drain(..length).collect();
+            // Header pages are the ones with no audio, so a granule position of 0
+            let granule = u64::from_le_bytes(page[6..14].try_into().unwrap());
+            self.in_headers = self.in_headers && granule == 0;
+
+            let mut station = self.station.lock().unwrap();
+            if self.in_headers {
+                station.headers.extend(&page);
+            }
+            let page = Arc::new(page);
+            // Drop anyone who's hung up or fallen too far behind
+            station.listeners.retain(|l| match l.try_send(page.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    eprintln!("Listener dropped: fell {} pages behind", MAX_BACKLOG_PAGES);
+                    false
+                },
+                Err(TrySendError::Disconnected(_)) => false
+            });
+        }
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+impl Destination for Pages {
+    fn rewrite(&mut self, _offset: u64, _bytes: &[u8]) -> io::Result<bool> {
+        return Ok(false);
+    }
+}
+
+// Length of the Ogg page at the start of buf, if it's all there
+fn page_length(buf: &[u8]) -> Option<usize> {
+    if buf.len() < 27 {
+        return None;
+    }
+    let segments = buf[26] as usize;
+    let header = 27 + segments;
+    if buf.len() < header {
+        return None;
+    }
+    let length = header + buf[27..header].iter().map(|s| *s as usize).sum::<usize>();
+    return if buf.len() >= length { Some(length) } else { None };
+}
+
+fn accept(listener: TcpListener, station: Arc<Mutex<Station>>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let station = station.clone();
+                spawn(move || {
+                    if let Err(err) = serve(stream, station) {
+                        if err.kind() != io::ErrorKind::BrokenPipe && err.kind() != io::ErrorKind::ConnectionReset {
+                            eprintln!("Listener dropped: {}", err);
+                        }
+                    }
+                });
+            },
+            Err(err) => eprintln!("Couldn't accept listener: {}", err)
+        }
+    }
+}
+
+fn serve(mut stream: TcpStream, station: Arc<Mutex<Station>>) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT_S)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT_S)))?;
+    let (path, icy) = read_request(&stream)?;
+    match path.as_str() {
+        "/" | "/stream.ogg" => (),
+        "/nowplaying" => {
+            let text = station.lock().unwrap().now_playing.clone();
+            write!(stream, "HTTP/1.0 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\n\r\n{}",
+                text.len(), text)?;
+            return Ok(());
+        },
+        _ => {
+            write!(stream, "HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n")?;
+            return Ok(());
+        }
+    }
+
+    // Join up and take a copy of the headers together, so no pages go missing in between
+    let (tx, rx) = sync_channel(MAX_BACKLOG_PAGES);
+    let (headers, name) = {
+        let mut station = station.lock().unwrap();
+        station.listeners.push(tx);
+        (station.headers.clone(), station.name.clone())
+    };
+
+    write!(stream, "HTTP/1.0 200 OK\r\nContent-Type: audio/ogg\r\nCache-Control: no-cache\r\nicy-name: {}\r\n", name)?;
+    if icy {
+        write!(stream, "icy-metaint: {}\r\n", ICY_METAINT)?;
+    }
+    write!(stream, "\r\n")?;
+    let mut out = IcyWriter { stream: stream, station: station, icy: icy, until_meta: ICY_METAINT, sent: None };
+    out.send(&headers)?;
+    for page in rx {
+        out.send(&page)?;
+    }
+    return Ok(());
+}
+
+// The request path, and whether the client wants ICY metadata
+fn read_request(stream: &TcpStream) -> io::Result<(String, bool)> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let path = line.split_whitespace().nth(1).unwrap_or("/").to_string();
+    let mut icy = false;
+    let mut read = line.len();
+    loop {
+        line.clear();
+        let n = reader.read_line(&mut line)?;
+        read += n;
+        if n == 0 || line.trim().is_empty() || read > MAX_REQUEST_BYTES {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("icy-metadata") && value.trim() == "1" {
+                icy = true;
+            }
+        }
+    }
+    return Ok((path, icy));
+}
+
+// Sends the stream, with an ICY metadata block every ICY_METAINT bytes if the client asked for them
+struct IcyWriter {
+    stream: TcpStream,
+    station: Arc<Mutex<Station>>,
+    icy: bool,
+    until_meta: usize,
+    sent: Option<String>, // Last now playing text the client got
+}
+
+impl IcyWriter {
+    fn send(&mut self, mut bytes: &[u8]) -> io::Result<()> {
+        if !self.icy {
+            return self.stream.write_all(bytes);
+        }
+        while !bytes.is_empty() {
+            let n = bytes.len().min(self.until_meta);
+            self.stream.write_all(&bytes[..n])?;
+            bytes = &bytes[n..];
+            self.until_meta -= n;
+            if self.until_meta == 0 {
+                let block = self.metadata();
+                self.stream.write_all(&block)?;
+                self.until_meta = ICY_METAINT;
+            }
+        }
+        return Ok(());
+    }
+
+    // Just the zero length if nothing's changed
+    fn metadata(&mut self) -> Vec<u8> {
+        let now_playing = self.station.lock().unwrap().now_playing.clone();
+        if self.sent.as_ref() == Some(&now_playing) {
+            return vec![0];
+        }
+        let block = icy_block(&now_playing);
+        self.sent = Some(now_playing);
+        return block;
+    }
+}
+
+// A length byte in 16s, then the text padded to fit
+fn icy_block(now_playing: &str) -> Vec<u8> {
+    let mut title = now_playing.replace('\'', "\u{2019}");
+    // Cut the title on a character boundary, leaving room for the rest of the block
+    let room = 255 * 16 - "StreamTitle='';".len();
+    if title.len() > room {
+        let end = (0..=room).rev().find(|i| title.is_char_boundary(*i)).unwrap_or(0);
+        title.truncate(end);
+    }
+    let mut text = format!("StreamTitle='{}';", title).into_bytes();
+    let blocks = (text.len() + 15) / 16;
+    text.resize(blocks * 16, 0);
+    let mut block = vec![blocks as u8];
+    block.extend(text);
+    return block;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_titles_are_cut_between_characters() {
+        for title in ["Short", &"é".repeat(3000), &"x".repeat(5000)] {
+            let block = icy_block(title);
+            assert_eq!(block.len(), 1 + block[0] as usize * 16);
+            let text = std::str::from_utf8(&block[1..]).unwrap().trim_end_matches('\0');
+            assert!(text.starts_with("StreamTitle='") && text.ends_with("';"), "{:?}", text);
+        }
+        let expected = "StreamTitle='It\u{2019}s 3-2';".as_bytes();
+        assert_eq!(&icy_block("It's 3-2")[1..1 + expected.len()], expected);
+    }
+}
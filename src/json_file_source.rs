@@ -6,38 +6,88 @@ use regex::Regex;
 use lazy_static::lazy_static;
 
 
-use crate::events::{PlayEvent, GameEvent, Team, Inning};
-use crate::json_file_source::json_types::GameEventData;
+use crate::events::{PlayEvent, GameEvent, GameInfo, Team, Inning, Hit};
+use crate::json_file_source::json_types::{GameEventData, LogEvent};
 
 mod json_types;
 
-pub fn new(filename: &str, tx: Sender<GameEvent>) -> JoinHandle<i32> {
-    let filepath = Path::new(filename);
-    let file = File::open(filepath).unwrap();
+// A game dump, read in and ready to play out
+pub struct GameDump {
+    pub info: GameInfo,
+    log: json_types::GameLog,
+}
+
+pub fn load(filename: &str) -> Result<GameDump, String> {
+    let file = File::open(Path::new(filename)).map_err(|err| format!("Can't open {}: {}", filename, err))?;
+    let log: json_types::GameLog = serde_json::from_reader(file)
+        .map_err(|err| format!("Can't read the game in {}: {}", filename, err))?;
+    return Ok(GameDump {
+        info: game_info(filename, &log),
+        log: log,
+    });
+}
+
+pub fn new(dump: GameDump, tx: Sender<GameEvent>) -> JoinHandle<i32> {
     return spawn(move || {
-        load_events_from_file(&file, tx);
+        load_events_from_log(dump.log, tx);
         return 0;
     });
 }
 
-fn load_events_from_file(file: &File, tx: Sender<GameEvent>) {
-    let log: json_types::GameLog = serde_json::from_reader(file).unwrap();
-
-    // These dumps don't contain match info, so hardcode it
-    tx.send(GameEvent::Pregame(Team {
+// These dumps don't contain match info, so hardcode it
+fn teams() -> (Team, Team) {
+    return (Team {
         full_name: "Charleston Shoe Thieves".to_string(), short_name: "Shoe Thieves".to_string()
     }, Team{
         full_name: "Mexico City Wild Wings".to_string(), short_name: "Wild Wings".to_string()
-    })).unwrap();
-    // tx.send(GameEvent::Pregame(Team {
+    });
+    // return (Team {
     //     full_name: "Breckenridge Jazz Hands".to_string(), short_name: "Jazz Hands".to_string()
     // }, Team{
     //     full_name: "Dallas Steaks".to_string(), short_name: "Steaks".to_string()
-    // })).unwrap();
+    // });
+}
 
-    for item in log.items {
+// What we can tell about the game before playing it out, for tagging the broadcast. The season
+// and day only come from file names like "S1D103_WingsVsShoeThieves_3.json".
+fn game_info(filename: &str, log: &json_types::GameLog) -> GameInfo {
+    lazy_static! {
+        static ref SEASON_DAY: Regex = Regex::new("^S(\\d+)D(\\d+)_").unwrap();
+    }
 
-        match translate_event(item.data) {
+    let first = log.items.first();
+    let (home, away) = teams();
+
+    let name = Path::new(filename).file_name().and_then(|n| n.to_str()).unwrap_or("");
+    let season_day = SEASON_DAY.captures(name);
+    let number = |i: usize| season_day.as_ref().and_then(|caps| caps[i].parse().ok());
+    return GameInfo {
+        title: format!("{} vs {}", home.short_name, away.short_name),
+        game_id: first.map(|item| item.game_id.clone()),
+        // Timestamps are UTC, so the date is too
+        date: first
+            .filter(|item| parse_timestamp(&item.timestamp).is_some())
+            .map(|item| item.timestamp[..10].to_string()),
+        season: number(1),
+        day: number(2),
+    };
+}
+
+fn load_events_from_log(log: json_types::GameLog, tx: Sender<GameEvent>) {
+    let (home, away) = teams();
+    tx.send(GameEvent::Pregame(home, away)).unwrap();
+
+    let start = log.items.first().and_then(|item| parse_timestamp(&item.timestamp));
+    for (i, item) in log.items.iter().enumerate() {
+
+        match start.zip(parse_timestamp(&item.timestamp)) {
+            Some((start, now)) => tx.send(GameEvent::Clock(now - start)).unwrap(),
+            None => ()
+        }
+
+        // The dump already knows how each play turns out, so a hit can sound like what it becomes
+        let upcoming = &log.items[i + 1..];
+        match translate_event(&item.data, upcoming) {
             Some(event) => tx.send(event).unwrap(),
             None => ()
         }
@@ -47,6 +97,30 @@ fn load_events_from_file(file: &File, tx: Sender<GameEvent>) {
     tx.send(GameEvent::EndBroadcast()).unwrap();
 }
 
+// Parse an ISO 8601 UTC timestamp like "2023-01-15T01:00:06.206Z" into seconds since the epoch
+fn parse_timestamp(timestamp: &str) -> Option<f64> {
+    lazy_static! {
+        static ref REGEX: Regex = Regex::new(
+            "^(\\d{4})-(\\d{2})-(\\d{2})T(\\d{2}):(\\d{2}):(\\d{2}(\\.\\d+)?)Z?$"
+        ).unwrap();
+    }
+
+    let caps = REGEX.captures(timestamp)?;
+    let field = |i: usize| caps[i].parse::<i64>().ok();
+    let (year, month, day) = (field(1)?, field(2)?, field(3)?);
+    let seconds: f64 = caps[6].parse().ok()?;
+
+    // Days since the epoch, from Howard Hinnant's days_from_civil
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    return Some((days * 86400 + field(4)? * 3600 + field(5)? * 60) as f64 + seconds);
+}
+
 fn extract_i32(data: &GameEventData, label: &str) -> Option<i32> {
     return match data.changedState.get(label) {
         Some(serde_json::Value::Number(n)) => Some(n),
@@ -67,22 +141,72 @@ fn is_complete(data: &GameEventData) -> bool {
     });
 }
 
-fn get_thwack(data: &GameEventData) -> f32 {
+fn get_thwack(data: &GameEventData, upcoming: &[LogEvent]) -> Option<Hit> {
     lazy_static! {
         static ref REGEX: Regex = Regex::new(
-        "(A [^ ]* hit to(ward)? .*\\.\\.\\.)|(.* ((hits)|(swats)|(slaps)|(rolls)|(drags)|(chops)|(thumps)|(bats)|(knocks)|(sputters)|(taps)|(pushes)|(clips)) ((it)|(the pitch)|(the ball)|(one)) (in)?to(wards?)? .*\\.\\.\\.)"
+        "(A [^ ]* hit to(ward)? .*\\.\\.\\.)|(.* ((hits)|(swats)|(slaps)|(smacks)|(whacks)|(punches)|(rolls)|(drags)|(chops)|(thumps)|(bats)|(knocks)|(sputters)|(taps)|(pushes)|(clips)) ((it)|(the pitch)|(the ball)|(one)) (in)?to(wards?)? .*\\.\\.\\.)"
         ).unwrap();
+        static ref SFX: Regex = Regex::new("^(BAM|BOOM|CRACK|SMACK|SMASH|THWACK|WHAM)!").unwrap();
+        static ref SOFT: Regex = Regex::new("( weak | depressing |sputters|taps|rolls|pushes|the Infield)").unwrap();
+        static ref DEEP: Regex = Regex::new("(Deep |the Wall)").unwrap();
     }
 
+    let text = &data.displayText;
     // Detect fouls
-    if data.displayText.starts_with("Foul ball") 
-    || data.displayText.contains(" fouls it ")
-    || data.displayText.contains(" hits a foul"){
-        return 0.3;
-    } else if REGEX.is_match(&data.displayText) {
-        return 1.2;
+    if text.starts_with("Foul ball")
+    || text.contains(" fouls it ")
+    || text.contains(" hits a foul"){
+        return Some(Hit::Foul);
+    } else if !REGEX.is_match(text) {
+        return None;
+    } else if home_run_ahead(upcoming) {
+        return Some(Hit::HomeRun);
     }
-    return 0.0;
+
+    // Grade by where it went, then a step up if it got a sound effect
+    let hit = if DEEP.is_match(text) {
+        Hit::DeepFly
+    } else if SOFT.is_match(text) {
+        Hit::WeakGrounder
+    } else {
+        Hit::LineDrive
+    };
+    return Some(match (hit, SFX.is_match(text)) {
+        (Hit::WeakGrounder, true) => Hit::LineDrive,
+        (Hit::LineDrive, true) => Hit::DeepFly,
+        (hit, _) => hit
+    });
+}
+
+// Whether the play in progress ends up leaving the park
+fn home_run_ahead(upcoming: &[LogEvent]) -> bool {
+    for event in upcoming.iter().take(8) {
+        let text = &event.data.displayText;
+        if text.contains("hits a Home Run") || text.contains("hits a grand slam") {
+            return true;
+        } else if text.contains("steps up to bat") || text.starts_with("End of") {
+            return false;
+        }
+    }
+    return false;
+}
+
+// How the batting team's fans take the play, from 0 to 1: they cheer hits and groan at outs
+fn get_reaction(data: &GameEventData) -> (f32, f32) {
+    let text = &data.displayText;
+    let yay = if text.contains("hits a Home Run") {
+        0.8
+    } else if (text.contains(" hits a ") || text.contains(" is on with a ")) && text.ends_with('!') {
+        0.4
+    } else {
+        0.0
+    };
+    // Only what's changed is listed, and the count goes back to 0 once the half-inning's over
+    let oh = match extract_i32(data, "outs") {
+        Some(outs) if outs > 0 => 0.2,
+        _ => 0.0
+    };
+    return (yay, oh);
 }
 
 fn clean_sfx(message: &str) -> String {
@@ -95,7 +219,7 @@ fn clean_sfx(message: &str) -> String {
     return REGEX.replace(message, "").to_string();
 }
 
-fn translate_event(data: GameEventData) -> Option<GameEvent> {
+fn translate_event(data: &GameEventData, upcoming: &[LogEvent]) -> Option<GameEvent> {
 
     if is_complete(&data) {
         return Some(GameEvent::GameEnd());
@@ -123,12 +247,13 @@ fn translate_event(data: GameEventData) -> Option<GameEvent> {
         let home_score = extract_i32(&data, "homeScore");
         let away_score = extract_i32(&data, "awayScore");
         let outs = extract_i32(&data, "outs");
-        let thwack = get_thwack(&data);
+        let thwack = get_thwack(data, upcoming);
+        let (yay, oh) = get_reaction(data);
         let event: PlayEvent = PlayEvent {
             message: clean_sfx(&data.displayText),
             thwack: thwack,
-            yay: 0.0,
-            oh: 0.0,
+            yay: yay,
+            oh: oh,
             home_score: home_score,
             away_score: away_score,
             outs: outs,
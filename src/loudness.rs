@@ -0,0 +1,188 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::iter::zip;
+
+use crate::effects::Biquad;
+use crate::types::{Samp, CHANNELS, sample_rate};
+
+// Loudness measurement as in EBU R128 / ITU-R BS.1770: K-weighted, in 400ms blocks overlapping by
+// 75%, gated at -70 LUFS and then 10 LU below the ungated level.
+
+const BLOCK_MS: u64 = 400;
+const HOPS_PER_BLOCK: usize = 4;
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+// Blocks are kept in a histogram of 0.1 LU bins, so gating doesn't mean going over the whole game
+const BINS_PER_LU: f64 = 10.0;
+const HISTOGRAM_TOP: f64 = 30.0;
+
+// The K-weighting curve: a high shelf for the head, then a high pass. Worked out from the analog
+// prototypes so it's right at any sample rate, not just 48kHz.
+fn k_weighting(rate: f64) -> (Biquad, Biquad) {
+    let f0 = 1681.974450955533;
+    let gain_db = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0]);
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0]);
+
+    return (shelf, high_pass);
+}
+
+// Measures interleaved stereo. Both channels count equally, as they do in BS.1770.
+pub struct LoudnessMeter {
+    filters: Vec<(Biquad, Biquad)>, // K-weighting for each channel
+    hop_length: usize,
+    hop_fill: usize,
+    hop_sum: f64,
+    hops: VecDeque<f64>, // Mean square of the last few hops
+    bins: Vec<(u64, f64)>, // Number of blocks and their summed mean square, by loudness
+}
+
+impl LoudnessMeter {
+
+    pub fn new() -> Self {
+        let rate = sample_rate().get();
+        return LoudnessMeter {
+            filters: (0..CHANNELS).map(|_| k_weighting(rate as f64)).collect(),
+            hop_length: ((BLOCK_MS * rate as u64 / 1000) as usize / HOPS_PER_BLOCK).max(1),
+            hop_fill: 0,
+            hop_sum: 0.0,
+            hops: VecDeque::new(),
+            bins: vec![(0, 0.0); ((HISTOGRAM_TOP - ABSOLUTE_GATE) * BINS_PER_LU) as usize],
+        };
+    }
+
+    pub fn process(&mut self, buf: &[Samp]) {
+        for frame in buf.chunks_exact(CHANNELS) {
+            for (s, (shelf, high_pass)) in zip(frame, &mut self.filters) {
+                let weighted = high_pass.process(shelf.process(*s as f64));
+                self.hop_sum += weighted * weighted;
+            }
+            self.hop_fill += 1;
+            if self.hop_fill == self.hop_length {
+                self.end_hop();
+            }
+        }
+    }
+
+    fn end_hop(&mut self) {
+        self.hops.push_back(self.hop_sum / self.hop_length as f64);
+        self.hop_sum = 0.0;
+        self.hop_fill = 0;
+        if self.hops.len() > HOPS_PER_BLOCK {
+            self.hops.pop_front();
+        }
+        if self.hops.len() == HOPS_PER_BLOCK {
+            let block = self.hops.iter().sum::<f64>() / HOPS_PER_BLOCK as f64;
+            if block > power(ABSOLUTE_GATE) {
+                let bin = (((lufs(block) - ABSOLUTE_GATE) * BINS_PER_LU) as usize).min(self.bins.len() - 1);
+                self.bins[bin].0 += 1;
+                self.bins[bin].1 += block;
+            }
+        }
+    }
+
+    // Seconds of audio that made it past the absolute gate
+    pub fn gated_seconds(&self) -> f64 {
+        let hop_seconds = self.hop_length as f64 / sample_rate().get() as f64;
+        return self.bins.iter().map(|(count, _)| *count).sum::<u64>() as f64 * hop_seconds;
+    }
+
+    // Integrated loudness of everything so far, in LUFS. None if it's all been silence.
+    pub fn integrated(&self) -> Option<f64> {
+        let loud_enough = gated_mean(&self.bins)?;
+        let relative_gate = lufs(loud_enough) + RELATIVE_GATE;
+        let first_bin = ((relative_gate - ABSOLUTE_GATE).max(0.0) * BINS_PER_LU) as usize;
+        return gated_mean(&self.bins[first_bin.min(self.bins.len())..]).map(lufs);
+    }
+}
+
+fn gated_mean(bins: &[(u64, f64)]) -> Option<f64> {
+    let (count, sum) = bins.iter()
+        .fold((0, 0.0), |(count, sum), (c, s)| (count + c, sum + s));
+    if count == 0 {
+        return None;
+    }
+    return Some(sum / count as f64);
+}
+
+fn lufs(mean_square: f64) -> f64 {
+    return -0.691 + 10.0 * mean_square.log10();
+}
+
+fn power(lufs: f64) -> f64 {
+    return 10f64.powf((lufs + 0.691) / 10.0);
+}
+
+pub fn db_to_gain(db: f32) -> f32 {
+    return 10f32.powf(db / 20.0);
+}
+
+pub fn gain_to_db(gain: f32) -> f32 {
+    return 20.0 * gain.log10();
+}
+
+// Estimates the peaks between samples by looking at the signal upsampled 4 times
+const OVERSAMPLING: usize = 4;
+const PEAK_TAPS: usize = 8;
+// How far behind the newest sample the peak reading is
+pub const PEAK_LATENCY: usize = PEAK_TAPS / 2 - 1;
+
+pub struct TruePeak {
+    phases: [[f32; PEAK_TAPS]; OVERSAMPLING],
+    history: [Samp; PEAK_TAPS],
+    max: f32,
+}
+
+impl TruePeak {
+
+    pub fn new() -> Self {
+        // Hann-windowed sinc, interpolating between the middle two samples of the history
+        let mut phases = [[0.0; PEAK_TAPS]; OVERSAMPLING];
+        for (p, phase) in phases.iter_mut().enumerate() {
+            let centre = (PEAK_TAPS / 2 - 1) as f64 + p as f64 / OVERSAMPLING as f64;
+            for (k, tap) in phase.iter_mut().enumerate() {
+                let x = k as f64 - centre;
+                let window = 0.5 * (1.0 + (PI * x / (PEAK_TAPS / 2) as f64).cos());
+                let sinc = if x.abs() < 1e-9 { 1.0 } else { (PI * x).sin() / (PI * x) };
+                *tap = (sinc * window) as f32;
+            }
+            let sum: f32 = phase.iter().sum();
+            phase.iter_mut().for_each(|t| *t /= sum);
+        }
+        return TruePeak {
+            phases: phases,
+            history: [0.0; PEAK_TAPS],
+            max: 0.0,
+        };
+    }
+
+    // Add a sample, and get the peak level around the sample PEAK_LATENCY ago
+    pub fn push(&mut self, s: Samp) -> f32 {
+        self.history.copy_within(1.., 0);
+        self.history[PEAK_TAPS - 1] = s;
+        let peak = self.phases.iter()
+            .map(|phase| phase.iter().zip(&self.history).map(|(t, h)| t * h).sum::<f32>().abs())
+            .fold(0.0, f32::max);
+        self.max = self.max.max(peak);
+        return peak;
+    }
+
+    // Loudest peak seen so far
+    pub fn max(&self) -> f32 {
+        return self.max;
+    }
+}
@@ -1,16 +1,46 @@
-use std::path::Path;
+use std::num::NonZeroU32;
+use std::str::FromStr;
+use std::path::{Path, PathBuf};
 
 use announce_channel::AnnounceChannel;
 use clap::Parser;
-use vorbis_output::output_to_vorbis;
+use announce_channel::AdaptiveRate;
+use mixer::{Mixer, Levels, Ducking};
+use effects::Preset;
+use sample_library::{Category, Selection, Selector};
+use master::{Master, MasterSettings};
+use tts::{Speaker, Prosody};
+use output::{Format, OutputError, OutputSpec, Settings, Sink, SinkThread, output_to_sinks};
+use transcript::{TranscriptFormat, TranscriptSink};
+use chapters::{ChapterFormat, ChapterSink};
+use split_output::{Split, SplitSink};
+use hls_output::HlsSink;
 
 mod tts;
+mod colour;
+mod markup;
+mod effects;
+mod loudness;
+mod master;
+mod resample;
+mod mixer;
 mod json_file_source;
 mod events;
 mod game_state;
 mod announce_channel;
 mod types;
+mod output;
 mod vorbis_output;
+mod wav_output;
+mod pcm_output;
+mod flac_output;
+mod opus_output;
+mod http_output;
+mod transcript;
+mod chapters;
+mod split_output;
+mod hls_output;
+mod pacing;
 mod sample_library;
 
 #[derive(Parser, Debug)]
@@ -18,27 +48,290 @@ mod sample_library;
 struct Args {
     #[arg(short, long)]
     file_source: String,
+    /// Output sample rate, in Hz. Voices and sound effects are resampled to match.
+    #[arg(long, default_value_t = types::DEFAULT_SAMPLE_RATE)]
+    sample_rate: u32,
+    /// Mimic 3 voice for the play-by-play announcer, as "voice" or "voice#speaker"
+    #[arg(long, default_value = "en_UK/apope_low")]
+    voice: String,
+    /// Mimic 3 voice for the colour commentator
+    #[arg(long, default_value = "en_US/vctk_low#p236")]
+    colour_voice: String,
+    /// Voice to switch to when the chosen one keeps failing
+    #[arg(long)]
+    fallback_voice: Option<String>,
+    /// Speaking time, higher is slower
+    #[arg(long, default_value_t = Prosody::default().length_scale)]
+    length_scale: f32,
+    /// Amount of variation in the voice
+    #[arg(long, default_value_t = Prosody::default().noise_scale)]
+    noise_scale: f32,
+    /// Amount of variation in phoneme lengths
+    #[arg(long, default_value_t = Prosody::default().noise_w)]
+    noise_w: f32,
+    /// Pass message markup (SSML) through to Mimic 3 instead of acting it out ourselves
+    #[arg(long)]
+    ssml: bool,
+    /// Volume of the announcers
+    #[arg(long, default_value_t = 1.0)]
+    voice_gain: f32,
+    /// Volume of one-shot sound effects like bat cracks
+    #[arg(long, default_value_t = 1.0)]
+    sfx_gain: f32,
+    /// Volume of the crowd
+    #[arg(long, default_value_t = 0.5)]
+    crowd_gain: f32,
+    /// Volume of music
+    #[arg(long, default_value_t = 0.6)]
+    music_gain: f32,
+    /// Effects on the announcers, sound effects, crowd and music
+    #[arg(long, value_enum, default_value_t = Preset::Clean)]
+    effects: Preset,
+    /// Amount of hiss and crackle from --effects old-time-radio, or 0 for none
+    #[arg(long, default_value_t = 1.0)]
+    radio_static: f32,
+    /// Time for the crowd and music to dip once the announcers start talking, in ms
+    #[arg(long, default_value_t = 60.0)]
+    duck_attack: f32,
+    /// Time for the crowd and music to come back up after talking, in ms
+    #[arg(long, default_value_t = 500.0)]
+    duck_release: f32,
+    /// How far the crowd and music dip under speech, in dB. 0 turns ducking off.
+    #[arg(long, default_value_t = 9.0)]
+    duck_depth: f32,
+    /// Loudness to bring the broadcast to, in LUFS
+    #[arg(long, default_value_t = -16.0)]
+    target_loudness: f32,
+    /// Leave the broadcast at whatever loudness it comes out at
+    #[arg(long)]
+    no_normalize: bool,
+    /// Highest true peak level allowed in the output, in dBTP
+    #[arg(long, default_value_t = -1.0)]
+    true_peak: f32,
+    /// Seed for all random choices, so the same seed and game give the same broadcast
+    #[arg(long)]
+    seed: Option<u64>,
+    /// How sound effects are picked
+    #[arg(long, value_enum, default_value_t = Selection::AvoidRecent)]
+    selection: Selection,
+    /// Number of recent picks avoid-recent won't repeat
+    #[arg(long, default_value_t = 2)]
+    avoid_recent: usize,
+    /// Speed up speech when the broadcast falls behind the game
+    #[arg(long)]
+    adaptive_rate: bool,
+    /// Fastest speed-up used by --adaptive-rate
+    #[arg(long, default_value_t = 1.5)]
+    max_speedup: f32,
+    /// Seconds behind the game at which --adaptive-rate reaches --max-speedup
+    #[arg(long, default_value_t = 20.0)]
+    catch_up: f32,
+    /// File to write the broadcast to, with any settings after it separated by commas: format=,
+    /// quality= for Vorbis (-0.1 to 1), bitrate= for Opus (in kbps) and split=. For example
+    /// "game.ogg,quality=0.8". Can be given more than once. Leave it out, or use "-", for stdout.
+    #[arg(short, long)]
+    output: Vec<OutputSpec>,
+    /// Output format for outputs that don't give one. Goes by the file extension if not given
+    /// either, otherwise Ogg Vorbis.
+    #[arg(long, value_enum)]
+    format: Option<Format>,
+    /// Split outputs into a file for each half-inning ("inning") or every so many seconds, named
+    /// after the output with a number on the end, plus an M3U playlist of them
+    #[arg(long)]
+    split: Option<Split>,
+    /// Stream the broadcast over HTTP on this port of localhost, in real time. There's no stdout
+    /// output unless asked for with -o -.
+    #[arg(long)]
+    serve: Option<u16>,
+    /// Vorbis quality of the HTTP stream, from -0.1 to 1 (0.5 if not given)
+    #[arg(long, value_parser = output::parse_quality)]
+    serve_quality: Option<f32>,
+    /// Write the broadcast as HLS into this directory, for browsers to play from a web server
+    #[arg(long)]
+    hls: Option<PathBuf>,
+    /// Segments the HLS playlist lists while the game's on, or 0 for all of them. It lists them
+    /// all once the game's over.
+    #[arg(long, default_value_t = 6)]
+    hls_window: usize,
+    /// Opus bitrate of the HLS stream, in kbps (96 if not given)
+    #[arg(long, value_parser = output::parse_bitrate)]
+    hls_bitrate: Option<u32>,
+    /// Send the broadcast out in real time rather than as fast as it can be made, filling in with
+    /// silence if the announcers aren't ready. For live listening with --hls; --serve always does.
+    #[arg(long)]
+    real_time: bool,
+    /// Write what's said to a subtitle file alongside the audio: .srt, .vtt, or .json for every
+    /// cue with its type. Can be given more than once.
+    #[arg(long)]
+    transcript: Vec<PathBuf>,
+    /// Write chapters for each half-inning to a cue sheet (.cue) or Ogg chapter comments (.txt).
+    /// WAV and FLAC files get them built in anyway.
+    #[arg(long)]
+    chapters: Option<PathBuf>,
+    /// Station name, tagged as the artist in the output
+    #[arg(long, default_value = "Bladio")]
+    station: String,
 }
 
 
 fn main() {
     let args = Args::parse();
 
-    let mut thwacks = sample_library::SampleLibrary::new(Path::new("sfx/thwack"));
+    match NonZeroU32::new(args.sample_rate) {
+        Some(rate) => types::set_sample_rate(rate).unwrap(),
+        None => {
+            eprintln!("Sample rate must be above zero");
+            std::process::exit(1);
+        }
+    }
+
+    types::seed_thread(args.seed, types::MAIN_STREAM);
+
+    if let Err(err) = tts::check_engine(args.ssml) {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+
+    let game = match json_file_source::load(&args.file_source) {
+        Ok(game) => game,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let comments = output::comments(&game.info, &args.station);
+    let mut sinks = Vec::new();
+    let outputs = if args.output.is_empty() && args.serve.is_none() && args.hls.is_none() {
+        vec![OutputSpec::from_str("-").unwrap()]
+    } else {
+        args.output.clone()
+    };
+    for spec in outputs {
+        let format = spec.format
+            .or(args.format)
+            .or_else(|| Format::from_path(&spec.path))
+            .unwrap_or(Format::Vorbis);
+        let comments = comments.clone();
+        let name = spec.path.display().to_string();
+        sinks.push(match spec.split.or(args.split) {
+            Some(_) if spec.path == Path::new("-") => {
+                eprintln!("Splitting needs an output file to name the pieces after");
+                std::process::exit(1);
+            },
+            Some(split) => start_sink(&name, move || {
+                return Ok(Box::new(SplitSink::new(&spec.path, format, spec.settings, &comments, split)?));
+            }),
+            None => start_sink(&name, move || {
+                return format.sink(output::open(Some(&spec.path))?, &comments, &spec.settings);
+            })
+        });
+    }
+
+    if let Some(port) = args.serve {
+        let (station, comments) = (args.station.clone(), comments.clone());
+        let mut settings = Settings::default();
+        settings.quality = args.serve_quality.unwrap_or(settings.quality);
+        sinks.push(start_sink("the HTTP stream", move || {
+            return Ok(Box::new(http_output::HttpSink::new(port, &station, &comments, &settings)?));
+        }));
+    }
+
+    if let Some(directory) = args.hls.clone() {
+        let window = if args.hls_window == 0 { None } else { Some(args.hls_window) };
+        let mut settings = Settings::default();
+        settings.bitrate = args.hls_bitrate.unwrap_or(settings.bitrate);
+        sinks.push(start_sink("the HLS stream", move || {
+            return Ok(Box::new(HlsSink::new(&directory, window, &settings)?));
+        }));
+    }
+
+    for path in args.transcript.iter() {
+        let format = match TranscriptFormat::from_path(path) {
+            Some(format) => format,
+            None => {
+                eprintln!("Don't know what sort of transcript {:?} is. Use .srt, .vtt or .json.", path);
+                std::process::exit(1);
+            }
+        };
+        let path = path.clone();
+        sinks.push(start_sink(&path.display().to_string(), move || {
+            return Ok(Box::new(TranscriptSink::new(output::open(Some(&path))?, format)?));
+        }));
+    }
+
+    if let Some(path) = args.chapters.clone() {
+        let format = match ChapterFormat::from_path(&path) {
+            Some(format) => format,
+            None => {
+                eprintln!("Don't know what sort of chapter file {:?} is. Use .cue or .txt.", path);
+                std::process::exit(1);
+            }
+        };
+        let audio_file = args.output.first().map(|spec| spec.path.clone());
+        let comments = comments.clone();
+        sinks.push(start_sink(&path.display().to_string(), move || {
+            let destination = output::open(Some(&path))?;
+            return Ok(Box::new(ChapterSink::new(destination, format, audio_file.as_deref(), &comments)?));
+        }));
+    }
+
+    let sfx = sample_library::SampleLibrary::new(Path::new("sfx"));
 
     let (game_tx, game_rx) = std::sync::mpsc::channel();
     let (announce_tx, announce_rx) = std::sync::mpsc::channel();
 
     // let _audio_thread
-    let source_thread = json_file_source::new(&args.file_source, game_tx);
-    let game_thread = game_state::spawn_game_thread(game_rx, announce_tx); 
-    let mut announcer = AnnounceChannel::new(announce_rx, &mut thwacks);
+    let source_thread = json_file_source::new(game, game_tx);
+    let game_thread = game_state::spawn_game_thread(game_rx, announce_tx, args.seed); 
+    let prosody = Prosody {
+        length_scale: args.length_scale,
+        noise_scale: args.noise_scale,
+        noise_w: args.noise_w,
+    };
+    // Listeners play the HTTP stream as it comes, so it can't run ahead of them
+    let real_time = args.real_time || args.serve.is_some();
+    let adaptive = if args.adaptive_rate {
+        Some(AdaptiveRate { max_speedup: args.max_speedup, catch_up: args.catch_up })
+    } else {
+        None
+    };
+    let announcer = AnnounceChannel::new(announce_rx, &sfx, Selector::new(args.selection, args.avoid_recent),
+        Box::new(Speaker::new(&args.voice, args.fallback_voice.as_deref(), prosody)),
+        Box::new(Speaker::new(&args.colour_voice, args.fallback_voice.as_deref(), prosody)),
+        args.ssml, adaptive, real_time);
+    let mixer = Mixer::new(announcer, Levels {
+        voice: args.voice_gain,
+        sfx: args.sfx_gain,
+        crowd: args.crowd_gain,
+        music: args.music_gain,
+    }, Ducking {
+        attack_ms: args.duck_attack,
+        release_ms: args.duck_release,
+        depth_db: args.duck_depth,
+    }, args.effects.effects(args.radio_static.max(0.0)), sfx.pick(Category::Ambience, &[]));
+    let mut master = Master::new(mixer, MasterSettings {
+        target_lufs: if args.no_normalize { None } else { Some(args.target_loudness) },
+        ceiling_db: args.true_peak,
+    });
     
-    let mut outstream = std::io::stdout();
-    let _encoder = output_to_vorbis(move |buf| {
-        announcer.next(buf)
-    }, &mut outstream).unwrap();
+    // Each sink's already reported what went wrong with it
+    if output_to_sinks(&mut master, sinks, real_time).is_err() {
+        std::process::exit(1);
+    }
+    eprintln!("{}", master.report());
 
     source_thread.join().unwrap();
     game_thread.join().unwrap();
 }
+
+// Start a sink in a thread of its own, or give up if it can't be opened
+fn start_sink<F>(name: &str, make: F) -> SinkThread
+    where F: FnOnce() -> Result<Box<dyn Sink>, OutputError> + Send + 'static {
+    return match SinkThread::spawn(name, make) {
+        Ok(sink) => sink,
+        Err(err) => {
+            eprintln!("Couldn't open {}: {}", name, err);
+            std::process::exit(1);
+        }
+    };
+}
@@ -0,0 +1,205 @@
+use regex::Regex;
+use lazy_static::lazy_static;
+
+// A small subset of SSML, for engines that can't read it themselves:
+//   <break time="300ms"/> or <break strength="strong"/>
+//   <emphasis>...</emphasis>
+//   <prosody rate="fast">...</prosody>
+// Anything else is dropped, keeping the text inside it.
+
+#[derive(Debug, PartialEq)]
+pub enum Segment {
+    Text(String, f32), // Text to speak, and how much faster than usual to speak it
+    Break(u32) // Pause, in milliseconds
+}
+
+const EMPHASIS_SPEEDUP: f32 = 0.85;
+
+lazy_static! {
+    static ref TAG: Regex = Regex::new("<(/?)([a-zA-Z-]+)([^>]*?)(/?)>").unwrap();
+}
+
+pub fn parse(message: &str) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    // Rate of each open tag
+    let mut rates: Vec<f32> = Vec::new();
+    let mut last = 0;
+
+    for caps in TAG.captures_iter(message) {
+        let tag = caps.get(0).unwrap();
+        push_text(&mut segments, &message[last..tag.start()], rates.iter().product());
+        last = tag.end();
+
+        let closing = !caps[1].is_empty();
+        let self_closing = !caps[4].is_empty();
+        let attrs = &caps[3];
+        match (&caps[2], closing) {
+            ("break", false) => segments.push(Segment::Break(break_length(attrs))),
+            ("emphasis", false) if !self_closing => rates.push(EMPHASIS_SPEEDUP),
+            ("prosody", false) if !self_closing => rates.push(attr(attrs, "rate").map_or(1.0, |r| parse_rate(&r))),
+            ("emphasis", true) | ("prosody", true) => { rates.pop(); },
+            _ => ()
+        }
+    }
+    push_text(&mut segments, &message[last..], rates.iter().product());
+
+    return segments;
+}
+
+// Just the words, for reading rather than speaking
+pub fn plain_text(message: &str) -> String {
+    let words: Vec<String> = parse(message).into_iter()
+        .filter_map(|segment| match segment {
+            Segment::Text(text, _) => Some(text),
+            Segment::Break(_) => None
+        })
+        .collect();
+    return words.join(" ").split_whitespace().collect::<Vec<_>>().join(" ");
+}
+
+// Wrap a message so it can be handed straight to an SSML engine. The text between tags is escaped,
+// so an ampersand in a team name doesn't break it, and tags we don't know are dropped as in parse().
+pub fn to_ssml(message: &str) -> String {
+    let mut ssml = String::new();
+    let mut last = 0;
+    for caps in TAG.captures_iter(message) {
+        let tag = caps.get(0).unwrap();
+        ssml += &escape(&unescape(&message[last..tag.start()]));
+        last = tag.end();
+        if matches!(&caps[2], "speak" | "break" | "emphasis" | "prosody") {
+            ssml += tag.as_str();
+        }
+    }
+    ssml += &escape(&unescape(&message[last..]));
+    if message.trim_start().starts_with("<speak") {
+        return ssml;
+    }
+    return format!("<speak>{}</speak>", ssml);
+}
+
+fn push_text(segments: &mut Vec<Segment>, text: &str, rate: f32) {
+    let text = unescape(text);
+    if text.trim().is_empty() {
+        return;
+    }
+    // Merge with the previous text if nothing changed in between
+    match segments.last_mut() {
+        Some(Segment::Text(prev, prev_rate)) if *prev_rate == rate => prev.push_str(&text),
+        _ => segments.push(Segment::Text(text, rate))
+    }
+}
+
+fn attr(attrs: &str, name: &str) -> Option<String> {
+    lazy_static! {
+        static ref ATTR: Regex = Regex::new("([a-zA-Z-]+)\\s*=\\s*[\"']([^\"']*)[\"']").unwrap();
+    }
+    return ATTR.captures_iter(attrs)
+        .find(|caps| &caps[1] == name)
+        .map(|caps| caps[2].to_string());
+}
+
+fn break_length(attrs: &str) -> u32 {
+    match attr(attrs, "time") {
+        Some(time) => {
+            let time = time.trim();
+            let ms = if let Some(ms) = time.strip_suffix("ms") {
+                ms.trim().parse::<f32>().ok()
+            } else if let Some(s) = time.strip_suffix('s') {
+                s.trim().parse::<f32>().ok().map(|s| s * 1000.0)
+            } else {
+                None
+            };
+            return ms.map_or(500, |ms| ms.max(0.0) as u32);
+        },
+        None => ()
+    }
+    return match attr(attrs, "strength").as_deref() {
+        Some("none") => 0,
+        Some("x-weak") => 100,
+        Some("weak") => 250,
+        Some("strong") => 750,
+        Some("x-strong") => 1000,
+        _ => 500
+    };
+}
+
+fn parse_rate(rate: &str) -> f32 {
+    return match rate.trim() {
+        "x-slow" => 0.6,
+        "slow" => 0.8,
+        "medium" | "default" => 1.0,
+        "fast" => 1.25,
+        "x-fast" => 1.5,
+        r => match r.strip_suffix('%') {
+            Some(pct) => pct.parse::<f32>().map_or(1.0, |p| p / 100.0),
+            None => r.parse::<f32>().unwrap_or(1.0)
+        }
+    }.max(0.1);
+}
+
+fn unescape(text: &str) -> String {
+    lazy_static! {
+        static ref ENTITY: Regex = Regex::new("&(#x[0-9a-fA-F]+|#[0-9]+|[a-z]+);").unwrap();
+    }
+    return ENTITY.replace_all(text, |caps: &regex::Captures| {
+        let entity = &caps[1];
+        let c = if let Some(hex) = entity.strip_prefix("#x") {
+            u32::from_str_radix(hex, 16).ok().and_then(char::from_u32)
+        } else if let Some(dec) = entity.strip_prefix('#') {
+            dec.parse::<u32>().ok().and_then(char::from_u32)
+        } else {
+            match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                _ => None
+            }
+        };
+        c.map_or(caps[0].to_string(), |c| c.to_string())
+    }).to_string();
+}
+
+fn escape(text: &str) -> String {
+    return text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_breaks_and_rates() {
+        let segments = parse("Next up: <break time=\"400ms\"/>Hades <emphasis>vs</emphasis> \
+            <prosody rate=\"fast\">Tigers</prosody><break strength=\"weak\"/><unknown>done</unknown>");
+        assert_eq!(segments, [
+            Segment::Text("Next up: ".to_string(), 1.0),
+            Segment::Break(400),
+            Segment::Text("Hades ".to_string(), 1.0),
+            Segment::Text("vs".to_string(), EMPHASIS_SPEEDUP),
+            Segment::Text("Tigers".to_string(), 1.25),
+            Segment::Break(250),
+            Segment::Text("done".to_string(), 1.0),
+        ]);
+        assert_eq!(parse("<break time=\"1.5s\"/>"), [Segment::Break(1500)]);
+        assert_eq!(parse("<prosody rate=\"50%\"><emphasis>slow</emphasis></prosody>"),
+            [Segment::Text("slow".to_string(), 0.5 * EMPHASIS_SPEEDUP)]);
+    }
+
+    #[test]
+    fn plain_text_keeps_just_the_words() {
+        assert_eq!(plain_text("Game over. <break time=\"300ms\"/> Mints 4,  <emphasis>Crabs</emphasis> 3."),
+            "Game over. Mints 4, Crabs 3.");
+        assert_eq!(plain_text("Salmon &amp; Sons &#x26; &lt;3"), "Salmon & Sons & <3");
+    }
+
+    #[test]
+    fn ssml_escapes_text_but_not_tags() {
+        assert_eq!(to_ssml("Game over. <break time=\"300ms\"/> Salmon & Sons <3 <emphasis>4</emphasis>."),
+            "<speak>Game over. <break time=\"300ms\"/> Salmon &amp; Sons &lt;3 <emphasis>4</emphasis>.</speak>");
+        // Entities already there aren't escaped twice, and tags we don't know are dropped
+        assert_eq!(to_ssml("A &amp; B <say-as>C</say-as>"), "<speak>A &amp; B C</speak>");
+        assert_eq!(to_ssml("<speak>A > B</speak>"), "<speak>A &gt; B</speak>");
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::VecDeque;
+use std::iter::zip;
+
+use crate::loudness::{LoudnessMeter, TruePeak, PEAK_LATENCY, db_to_gain, gain_to_db};
+use crate::mixer::Mixer;
+use crate::output::Marker;
+use crate::types::{Samp, CHANNELS, sample_rate, ms_to_samples};
+
+// The last stage before output: brings the mix to a target loudness, then limits it so it never
+// goes over the peak ceiling.
+
+pub struct MasterSettings {
+    pub target_lufs: Option<f32>, // None to leave the level alone
+    pub ceiling_db: f32, // Highest true peak allowed out, in dBTP
+}
+
+const MAX_NORMALIZE_DB: f32 = 12.0; // Most we'll turn the mix up or down
+const NORMALIZE_AFTER_S: f64 = 3.0; // Audio needed before the loudness reading is any use
+const NORMALIZE_TIME_S: f32 = 5.0; // Time taken to settle on a new gain
+const LOOKAHEAD_MS: u64 = 5;
+const LIMITER_RELEASE_MS: f32 = 80.0;
+
+// Lookahead limiter. The gain for each frame is the lowest any frame in the next LOOKAHEAD_MS
+// needs, averaged over the same window so it ramps down smoothly ahead of the peak. Both channels
+// get the same gain, so the stereo image doesn't shift.
+struct Limiter {
+    ceiling: f32,
+    lookahead: usize,
+    latency: usize, // Delay from input to output, in frames
+    detectors: Vec<TruePeak>,
+    delay: VecDeque<Samp>,
+    needed: VecDeque<(usize, f32)>, // Rising run of gains needed, with the sample they're for
+    held: VecDeque<f32>, // Lowest gain needed in the lookahead, for each delayed sample
+    held_sum: f64,
+    release: f32,
+    gain: f32,
+    count: usize,
+}
+
+impl Limiter {
+
+    fn new(ceiling_db: f32) -> Self {
+        let lookahead = (ms_to_samples(LOOKAHEAD_MS) as usize).max(1);
+        let release_samples = LIMITER_RELEASE_MS / 1000.0 * sample_rate().get() as f32;
+        return Limiter {
+            ceiling: db_to_gain(ceiling_db),
+            lookahead: lookahead,
+            latency: lookahead - 1 + PEAK_LATENCY,
+            detectors: (0..CHANNELS).map(|_| TruePeak::new()).collect(),
+            delay: VecDeque::new(),
+            needed: VecDeque::new(),
+            held: VecDeque::new(),
+            held_sum: 0.0,
+            release: (-1.0 / release_samples.max(1.0)).exp(),
+            gain: 1.0,
+            count: 0,
+        };
+    }
+
+    // Take in a frame and swap it for the one from latency frames ago, turned down if need be
+    fn process(&mut self, frame: &mut [Samp]) {
+        let peak = zip(&mut self.detectors, frame.iter())
+            .map(|(detector, s)| detector.push(*s))
+            .fold(0.0, f32::max);
+        let needed = if peak > self.ceiling { self.ceiling / peak } else { 1.0 };
+
+        // Sliding minimum over the lookahead window
+        while self.needed.back().map_or(false, |(_, g)| *g >= needed) {
+            self.needed.pop_back();
+        }
+        self.needed.push_back((self.count, needed));
+        while self.needed.front().map_or(false, |(i, _)| *i + self.lookahead <= self.count) {
+            self.needed.pop_front();
+        }
+        self.count += 1;
+        let lowest = self.needed.front().unwrap().1;
+
+        // Sliding average of that, so the gain ramps down over the lookahead
+        self.held.push_back(lowest);
+        self.held_sum += lowest as f64;
+        if self.held.len() > self.lookahead {
+            self.held_sum -= self.held.pop_front().unwrap() as f64;
+        }
+        let target = (self.held_sum / self.lookahead as f64) as f32
+            + (self.lookahead - self.held.len()) as f32 / self.lookahead as f32;
+
+        // Straight down, gently back up
+        self.gain = if target < self.gain {
+            target
+        } else {
+            target + (self.gain - target) * self.release
+        };
+
+        self.delay.extend(frame.iter());
+        for s in frame.iter_mut() {
+            *s = if self.delay.len() > self.latency * CHANNELS {
+                self.delay.pop_front().unwrap() * self.gain
+            } else {
+                0.0
+            };
+        }
+    }
+}
+
+pub struct Master<'a> {
+    mixer: Mixer<'a>,
+    target_lufs: Option<f32>,
+    gain: f32,
+    normalize_rate: f32,
+    limiter: Limiter,
+    input_meter: LoudnessMeter, // Drives the normalizer
+    output_meter: LoudnessMeter, // For the report at the end
+    output_peaks: Vec<TruePeak>,
+    tail: usize, // Samples left in the limiter once the mix is over
+}
+
+impl<'a> Master<'a> {
+
+    pub fn new(mixer: Mixer<'a>, settings: MasterSettings) -> Self {
+        let limiter = Limiter::new(settings.ceiling_db);
+        let tail = limiter.latency;
+        return Master {
+            mixer: mixer,
+            target_lufs: settings.target_lufs,
+            gain: 1.0,
+            normalize_rate: 1.0 - (-1.0 / (NORMALIZE_TIME_S * sample_rate().get() as f32)).exp(),
+            limiter: limiter,
+            input_meter: LoudnessMeter::new(),
+            output_meter: LoudnessMeter::new(),
+            output_peaks: (0..CHANNELS).map(|_| TruePeak::new()).collect(),
+            tail: tail * CHANNELS,
+        };
+    }
+
+    pub fn next(&mut self, buf: &mut [Samp]) -> usize {
+        let mut samples_filled = self.mixer.next(buf);
+        self.input_meter.process(&buf[..samples_filled]);
+
+        // Once the mix is over, push out what's left in the limiter
+        if samples_filled < buf.len() && self.tail > 0 {
+            let flush = self.tail.min(buf.len() - samples_filled);
+            buf[samples_filled..samples_filled + flush].fill(0.0);
+            samples_filled += flush;
+            self.tail -= flush;
+        }
+
+        let target_gain = self.target_gain();
+        for frame in buf[..samples_filled].chunks_exact_mut(CHANNELS) {
+            self.gain += (target_gain - self.gain) * self.normalize_rate;
+            frame.iter_mut().for_each(|s| *s *= self.gain);
+            self.limiter.process(frame);
+            for (peak, s) in zip(&mut self.output_peaks, frame.iter()) {
+                peak.push(*s);
+            }
+        }
+        self.output_meter.process(&buf[..samples_filled]);
+        return samples_filled;
+    }
+
+    // Whether the mix is over and the limiter's been emptied out
+    pub fn is_finished(&self) -> bool {
+        return self.mixer.is_finished() && self.tail == 0;
+    }
+
+    // Markers from the mix, moved later to allow for the limiter's delay
+    pub fn take_markers(&mut self) -> Vec<(u64, Marker)> {
+        let latency = self.limiter.latency as u64;
+        return self.mixer.take_markers().into_iter()
+            .map(|(frame, marker)| (frame + latency, marker))
+            .collect();
+    }
+
+    // Gain to bring what we've heard so far up (or down) to the target
+    fn target_gain(&self) -> f32 {
+        if self.input_meter.gated_seconds() < NORMALIZE_AFTER_S {
+            return self.gain;
+        }
+        return match self.target_lufs.zip(self.input_meter.integrated()) {
+            Some((target, measured)) => {
+                db_to_gain((target - measured as f32).clamp(-MAX_NORMALIZE_DB, MAX_NORMALIZE_DB))
+            },
+            None => self.gain
+        };
+    }
+
+    // Describe how loud the output came out
+    pub fn report(&self) -> String {
+        let loudness = match self.output_meter.integrated() {
+            Some(lufs) => format!("{:.1} LUFS", lufs),
+            None => "silent".to_string()
+        };
+        let max_peak = self.output_peaks.iter().map(|p| p.max()).fold(0.0, f32::max);
+        let peak = if max_peak > 0.0 {
+            format!("{:.1} dBTP", gain_to_db(max_peak))
+        } else {
+            "-inf dBTP".to_string()
+        };
+        return format!("Integrated loudness {}, true peak {}", loudness, peak);
+    }
+}
@@ -0,0 +1,389 @@
+use std::f32::consts::{PI, SQRT_2};
+use std::iter::zip;
+
+use crate::announce_channel::AnnounceChannel;
+use crate::sample_library::Sample;
+use crate::effects::{BusEffects, smoothing};
+use crate::output::Marker;
+use crate::types::{Samp, CHANNELS};
+
+#[derive(Clone, Copy)]
+pub enum BusId {
+    Sfx, // One-shots like thwacks
+    Crowd,
+    Music
+}
+
+// A sound for the mixer to start, at an offset into the current block
+pub struct Cue<'a> {
+    pub offset: usize,
+    pub bus: BusId,
+    pub sample: &'a Sample,
+    pub gain: f32,
+    pub rate: f32, // Playback speed, which also shifts the pitch
+    pub pan: f32, // -1 for hard left to 1 for hard right
+    pub fade_in: usize, // Frames
+    pub looped: bool, // Keep going until faded out
+}
+
+// Fade out everything on a bus, from an offset into the current block
+pub struct FadeOut {
+    pub offset: usize,
+    pub bus: BusId,
+    pub length: usize, // Frames
+}
+
+pub struct Levels {
+    pub voice: f32,
+    pub sfx: f32,
+    pub crowd: f32,
+    pub music: f32,
+}
+
+// How the crowd and music dip under the announcers
+pub struct Ducking {
+    pub attack_ms: f32, // Time to dip once speech starts
+    pub release_ms: f32, // Time to recover once it stops
+    pub depth_db: f32, // How far to dip, 0 for no ducking
+}
+
+// Level above which the announcer counts as talking
+const SPEECH_THRESHOLD: f32 = 0.02;
+// How long the speech detector holds on between words
+const DETECT_RELEASE_MS: f32 = 150.0;
+
+// Follows the announcer's level and works out how much to turn the background down
+struct Ducker {
+    depth: f32, // Gain while ducked
+    attack: f32, // Per-sample smoothing coefficients
+    release: f32,
+    detect_release: f32,
+    envelope: f32,
+    gain: f32,
+}
+
+impl Ducker {
+
+    fn new(ducking: &Ducking) -> Self {
+        return Ducker {
+            depth: 10f32.powf(-ducking.depth_db.max(0.0) / 20.0),
+            attack: smoothing(ducking.attack_ms),
+            release: smoothing(ducking.release_ms),
+            detect_release: smoothing(DETECT_RELEASE_MS),
+            envelope: 0.0,
+            gain: 1.0,
+        };
+    }
+
+    // Turn buf (interleaved stereo) down wherever the sidechain (mono) has speech in it
+    fn duck(&mut self, sidechain: &[Samp], buf: &mut [Samp]) {
+        for (i, frame) in buf.chunks_exact_mut(CHANNELS).enumerate() {
+            let level = sidechain.get(i).map_or(0.0, |s| s.abs());
+            self.envelope = if level > self.envelope {
+                level
+            } else {
+                level + (self.envelope - level) * self.detect_release
+            };
+            let (target, coeff) = if self.envelope > SPEECH_THRESHOLD {
+                (self.depth, self.attack)
+            } else {
+                (1.0, self.release)
+            };
+            self.gain = target + (self.gain - target) * coeff;
+            frame.iter_mut().for_each(|s| *s *= self.gain);
+        }
+    }
+}
+
+struct Playing<'a> {
+    sample: &'a Sample,
+    position: f64,
+    rate: f64,
+    delay: usize, // Frames until it starts
+    gain: f32,
+    pan: (f32, f32), // Left and right gains
+    looping: bool,
+    wide: bool, // Spread a mono loop across both sides
+    played: usize, // Frames so far
+    fade_in: usize, // Frames
+    fade_out: Option<Fade>,
+}
+
+struct Fade {
+    delay: usize, // Frames until it starts
+    length: usize,
+    done: usize,
+}
+
+impl<'a> Playing<'a> {
+    // Volume for the next frame, going by the fades
+    fn envelope(&mut self) -> f32 {
+        let mut level = if self.played < self.fade_in {
+            self.played as f32 / self.fade_in as f32
+        } else {
+            1.0
+        };
+        self.played += 1;
+        if let Some(fade) = &mut self.fade_out {
+            if fade.delay > 0 {
+                fade.delay -= 1;
+            } else {
+                level *= 1.0 - (fade.done as f32 / fade.length.max(1) as f32).min(1.0);
+                fade.done += 1;
+            }
+        }
+        return level;
+    }
+
+    fn faded_out(&self) -> bool {
+        return self.fade_out.as_ref().map_or(false, |f| f.done >= f.length);
+    }
+}
+
+// Gains for each side at a position from -1 (left) to 1 (right). Mono sounds keep the same power
+// wherever they are, and are at their original level in the centre; stereo sounds are balanced.
+fn pan_gains(pan: f32, stereo: bool) -> (f32, f32) {
+    let pan = pan.clamp(-1.0, 1.0);
+    if stereo {
+        return ((1.0 - pan).min(1.0), (1.0 + pan).min(1.0));
+    }
+    let angle = (pan + 1.0) * PI / 4.0;
+    return (angle.cos() * SQRT_2, angle.sin() * SQRT_2);
+}
+
+// Plays any number of overlapping sounds
+struct Player<'a> {
+    playing: Vec<Playing<'a>>,
+}
+
+impl<'a> Player<'a> {
+
+    fn new() -> Self {
+        return Player {
+            playing: Vec::new()
+        };
+    }
+
+    fn play(&mut self, cue: &Cue<'a>) {
+        self.start(cue.sample, cue.gain, cue.rate, cue.pan, cue.offset, cue.fade_in, cue.looped, false);
+    }
+
+    // Play a sound over and over, until the broadcast ends. Only the part between its loop points
+    // repeats, if it has them. Mono beds are spread across both sides.
+    fn play_bed(&mut self, sample: &'a Sample, gain: f32) {
+        self.start(sample, gain, 1.0, 0.0, 0, 0, true, true);
+    }
+
+    fn start(&mut self, sample: &'a Sample, gain: f32, rate: f32, pan: f32, delay: usize, fade_in: usize,
+        looping: bool, wide: bool) {
+        if sample.len() == 0 || rate <= 0.0 {
+            return;
+        }
+        self.playing.push(Playing {
+            sample: sample,
+            position: 0.0,
+            rate: rate as f64,
+            delay: delay,
+            gain: gain * sample.gain,
+            pan: pan_gains(pan, sample.audio.len() > 1),
+            looping: looping,
+            wide: wide,
+            played: 0,
+            fade_in: fade_in,
+            fade_out: None,
+        });
+    }
+
+    // Fade out everything that's playing, starting delay frames from now
+    fn fade_out(&mut self, delay: usize, length: usize) {
+        for p in self.playing.iter_mut().filter(|p| p.fade_out.is_none()) {
+            p.fade_out = Some(Fade { delay: delay.saturating_sub(p.delay), length: length, done: 0 });
+        }
+    }
+
+    // Loops don't count, or we'd never stop
+    fn is_playing(&self) -> bool {
+        return self.playing.iter().any(|p| !p.looping);
+    }
+
+    // Add whatever's playing on top of buf, which is interleaved stereo
+    fn mix_into(&mut self, buf: &mut [Samp], gain: f32) {
+        for p in self.playing.iter_mut() {
+            let frames = buf.len() / CHANNELS;
+            let start = p.delay.min(frames);
+            p.delay -= start;
+            let (loop_start, end) = match (p.looping, p.sample.loop_points) {
+                (true, Some(points)) => points,
+                _ => (0, p.sample.len())
+            };
+            // Play a mono bed from two different places at once, so it fills the stereo field
+            let sample = p.sample;
+            let (left, right, spread) = match &sample.audio[..] {
+                [mono] => (mono, mono, if p.wide { (end - loop_start) as f64 / 2.0 } else { 0.0 }),
+                [left, right, ..] => (left, right, 0.0),
+                [] => continue
+            };
+            for frame in buf[start * CHANNELS..].chunks_exact_mut(CHANNELS) {
+                if p.position >= end as f64 {
+                    if !p.looping {
+                        break;
+                    }
+                    p.position = loop_start as f64 + (p.position - end as f64) % (end - loop_start) as f64;
+                }
+                if p.faded_out() {
+                    break;
+                }
+                let level = p.envelope() * p.gain * gain;
+                let l = sample_at(left, p.position, loop_start, end, p.looping);
+                let r = sample_at(right, p.position + spread, loop_start, end, p.looping);
+                frame[0] += l * p.pan.0 * level;
+                frame[1] += r * p.pan.1 * level;
+                p.position += p.rate;
+            }
+        }
+        self.playing.retain(|p| !p.faded_out() && (p.looping || p.position < p.sample.len() as f64));
+    }
+}
+
+// Linear interpolation between samples, for rates other than 1. Positions past the end wrap
+// round if looping.
+fn sample_at(audio: &[Samp], position: f64, loop_start: usize, end: usize, looping: bool) -> Samp {
+    let wrap = |i: usize| if looping && i >= end { loop_start + (i - end) % (end - loop_start) } else { i };
+    let i = wrap(position as usize);
+    if i >= end {
+        return 0.0;
+    }
+    let next = wrap(i + 1);
+    let next = if next < end { audio[next] } else { 0.0 };
+    let frac = (position - position.floor()) as Samp;
+    return audio[i] + (next - audio[i]) * frac;
+}
+
+// Sums the announcer with the sound effect, crowd and music buses, in stereo
+pub struct Mixer<'a> {
+    announcer: AnnounceChannel<'a>,
+    levels: Levels,
+    effects: BusEffects,
+    sfx: Player<'a>,
+    crowd: Player<'a>,
+    music: Player<'a>,
+    ducker: Ducker,
+    voice: Vec<Samp>, // Announcer output, which is mono
+    bus: Vec<Samp>, // Each bus in turn, for its effects
+    background: Vec<Samp>, // Crowd and music, before ducking
+    ring_out: Option<usize>, // Frames left for effects to die away, once everything's stopped
+}
+
+impl<'a> Mixer<'a> {
+
+    pub fn new(announcer: AnnounceChannel<'a>, levels: Levels, ducking: Ducking, effects: BusEffects,
+        crowd_bed: Option<&'a Sample>) -> Self {
+        let mut crowd = Player::new();
+        if let Some(bed) = crowd_bed {
+            crowd.play_bed(bed, 1.0);
+        }
+        return Mixer {
+            announcer: announcer,
+            levels: levels,
+            effects: effects,
+            sfx: Player::new(),
+            crowd: crowd,
+            music: Player::new(),
+            ducker: Ducker::new(&ducking),
+            voice: Vec::new(),
+            bus: Vec::new(),
+            background: Vec::new(),
+            ring_out: None,
+        };
+    }
+
+    // Fill buf with interleaved stereo, and return the number of samples (not frames) filled
+    pub fn next(&mut self, buf: &mut [Samp]) -> usize {
+        let frames = buf.len() / CHANNELS;
+        self.voice.resize(frames, 0.0);
+        let mut frames_filled = self.announcer.next(&mut self.voice[..frames]);
+        self.voice.truncate(frames_filled);
+
+        for fade in self.announcer.take_fades() {
+            self.player(fade.bus).fade_out(fade.offset, fade.length);
+        }
+        for cue in self.announcer.take_cues() {
+            self.player(cue.bus).play(&cue);
+        }
+
+        // Let anything still playing ring out once the announcer's done, then the effects
+        if frames_filled < frames {
+            if self.is_playing() {
+                frames_filled = frames;
+            } else {
+                let ring_out = self.ring_out.get_or_insert(self.tail());
+                let extra = (*ring_out).min(frames - frames_filled);
+                *ring_out -= extra;
+                frames_filled += extra;
+            }
+        }
+
+        // The announcers sit in the centre
+        let out = &mut buf[..frames_filled * CHANNELS];
+        for (i, frame) in out.chunks_exact_mut(CHANNELS).enumerate() {
+            frame.fill(self.voice.get(i).copied().unwrap_or(0.0) * self.levels.voice);
+        }
+        self.effects.voice.process(out);
+
+        self.bus.clear();
+        self.bus.resize(out.len(), 0.0);
+        self.sfx.mix_into(&mut self.bus, self.levels.sfx);
+        self.effects.sfx.process(&mut self.bus);
+        mix(out, &self.bus);
+
+        self.background.clear();
+        self.background.resize(out.len(), 0.0);
+        self.bus.fill(0.0);
+        self.crowd.mix_into(&mut self.bus, self.levels.crowd);
+        self.effects.crowd.process(&mut self.bus);
+        mix(&mut self.background, &self.bus);
+        self.bus.fill(0.0);
+        self.music.mix_into(&mut self.bus, self.levels.music);
+        self.effects.music.process(&mut self.bus);
+        mix(&mut self.background, &self.bus);
+        self.ducker.duck(&self.voice, &mut self.background);
+        mix(out, &self.background);
+
+        self.effects.master.process(out);
+        return out.len();
+    }
+
+    // Whether the announcers are done and everything's rung out, so there's nothing more to come
+    pub fn is_finished(&self) -> bool {
+        return self.announcer.is_finished() && !self.is_playing() && self.ring_out == Some(0);
+    }
+
+    // Markers from the announcer. Its frames line up with ours.
+    pub fn take_markers(&mut self) -> Vec<(u64, Marker)> {
+        return self.announcer.take_markers();
+    }
+
+    fn player(&mut self, bus: BusId) -> &mut Player<'a> {
+        return match bus {
+            BusId::Sfx => &mut self.sfx,
+            BusId::Crowd => &mut self.crowd,
+            BusId::Music => &mut self.music
+        };
+    }
+
+    fn is_playing(&self) -> bool {
+        return self.sfx.is_playing() || self.crowd.is_playing() || self.music.is_playing();
+    }
+
+    // Longest any bus's effects keep going after it stops
+    fn tail(&self) -> usize {
+        let buses = [&self.effects.voice, &self.effects.sfx, &self.effects.crowd, &self.effects.music];
+        return buses.iter().map(|c| c.tail()).max().unwrap_or(0) + self.effects.master.tail();
+    }
+}
+
+fn mix(out: &mut [Samp], bus: &[Samp]) {
+    for (o, b) in zip(out.iter_mut(), bus) {
+        *o += *b;
+    }
+}
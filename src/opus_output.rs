@@ -0,0 +1,213 @@
+use audiopus::{Application, Bitrate, Channels, SampleRate};
+use audiopus::coder::Encoder;
+use ogg::writing::{PacketWriter, PacketWriteEndInfo};
+
+use crate::output::{Destination, OutputError, Sink, comment_header};
+use crate::resample::Resampler;
+use crate::types::{Samp, CHANNELS, sample_rate};
+
+// Ogg Opus, as in RFC 7845. Opus only runs at a few rates, so everything is resampled to 48kHz.
+
+pub const OPUS_RATE: u32 = 48000;
+pub const FRAME_SIZE: usize = 960; // 20ms at 48kHz
+const MAX_PACKET: usize = 4000; // Recommended by libopus
+const SERIAL: u32 = 0x626c6164;
+
+pub struct OpusSink {
+    writer: PacketWriter<Box<dyn Destination>>,
+    encoder: OpusEncoder,
+    granule: u64, // Samples at 48kHz encoded so far, the pre-skip included
+}
+
+impl OpusSink {
+
+    pub fn new(destination: Box<dyn Destination>, comments: &[(String, String)], bitrate: u32) -> Result<Self, OutputError> {
+        let encoder = OpusEncoder::new(bitrate)?;
+        let mut writer = PacketWriter::new(destination);
+        let mut head = Vec::new();
+        head.extend(b"OpusHead");
+        head.push(1); // Version
+        head.push(CHANNELS as u8);
+        head.extend((encoder.pre_skip as u16).to_le_bytes());
+        head.extend(sample_rate().get().to_le_bytes()); // Rate before we resampled, for information
+        head.extend(0i16.to_le_bytes()); // Output gain
+        head.push(0); // Mono or stereo, no mapping table
+        writer.write_packet(head.into_boxed_slice(), SERIAL, PacketWriteEndInfo::EndPage, 0)?;
+
+        let mut tags = Vec::new();
+        tags.extend(b"OpusTags");
+        tags.extend(comment_header(comments));
+        writer.write_packet(tags.into_boxed_slice(), SERIAL, PacketWriteEndInfo::EndPage, 0)?;
+
+        return Ok(OpusSink {
+            writer: writer,
+            encoder: encoder,
+            granule: 0,
+        });
+    }
+
+    fn write_packets(&mut self, packets: Vec<Vec<u8>>, last: bool) -> Result<(), OutputError> {
+        let count = packets.len();
+        for (i, packet) in packets.into_iter().enumerate() {
+            self.granule += FRAME_SIZE as u64;
+            // Players cut the end off at the final granule, so that has to cover all the real audio
+            let (info, granule) = if last && i == count - 1 {
+                (PacketWriteEndInfo::EndStream, self.encoder.end_granule())
+            } else {
+                (PacketWriteEndInfo::NormalPacket, self.granule)
+            };
+            self.writer.write_packet(packet.into_boxed_slice(), SERIAL, info, granule)?;
+        }
+        return Ok(());
+    }
+}
+
+impl Sink for OpusSink {
+    fn write(&mut self, buf: &[Samp]) -> Result<(), OutputError> {
+        let packets = self.encoder.encode(buf, false)?;
+        return self.write_packets(packets, false);
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), OutputError> {
+        let packets = self.encoder.encode(&[], true)?;
+        self.write_packets(packets, true)?;
+        self.writer.inner_mut().flush()?;
+        return Ok(());
+    }
+}
+
+// Resamples to 48kHz and encodes FRAME_SIZE frames at a time into Opus packets, for whatever
+// container they're going in
+pub struct OpusEncoder {
+    encoder: Encoder,
+    resamplers: Option<Vec<Resampler>>, // None if we're already at 48kHz
+    planar: Vec<Vec<Samp>>, // Resampled, waiting for a whole frame
+    frame: Vec<Samp>, // Interleaved for the encoder
+    packet: Vec<u8>,
+    pub pre_skip: u64, // Encoder delay, which players drop from the start
+    samples_in: u64, // Per channel, at 48kHz
+    samples_out: u64, // Encoded so far, the pre-skip included
+}
+
+impl OpusEncoder {
+
+    pub fn new(bitrate: u32) -> Result<Self, OutputError> {
+        let mut encoder = Encoder::new(SampleRate::Hz48000, Channels::Stereo, Application::Audio)?;
+        encoder.set_bitrate(Bitrate::BitsPerSecond(bitrate as i32))?;
+        let pre_skip = encoder.lookahead()? as u64;
+        let rate = sample_rate().get();
+        return Ok(OpusEncoder {
+            encoder: encoder,
+            resamplers: if rate == OPUS_RATE {
+                None
+            } else {
+                Some((0..CHANNELS).map(|_| Resampler::new(rate, OPUS_RATE)).collect())
+            },
+            planar: vec![Vec::new(); CHANNELS],
+            frame: vec![0.0; FRAME_SIZE * CHANNELS],
+            packet: vec![0; MAX_PACKET],
+            pre_skip: pre_skip,
+            samples_in: 0,
+            samples_out: 0,
+        });
+    }
+
+    // Where the real audio ends, counting the pre-skip, at 48kHz
+    pub fn end_granule(&self) -> u64 {
+        return self.pre_skip + self.samples_in;
+    }
+
+    // Packets for as many whole frames as there are. The last call pads out with silence to cover
+    // all the audio.
+    pub fn encode(&mut self, buf: &[Samp], last: bool) -> Result<Vec<Vec<u8>>, OutputError> {
+        self.push(buf, last);
+        let available = self.planar.iter().map(|c| c.len()).min().unwrap_or(0);
+        let mut frames = available / FRAME_SIZE;
+        if last {
+            frames = ((self.end_granule().saturating_sub(self.samples_out) as usize + FRAME_SIZE - 1) / FRAME_SIZE).max(1);
+            self.planar.iter_mut().for_each(|c| c.resize(frames * FRAME_SIZE, 0.0));
+        }
+        let mut packets = Vec::with_capacity(frames);
+        for i in 0..frames {
+            for (c, channel) in self.planar.iter().enumerate() {
+                let input = &channel[i * FRAME_SIZE..(i + 1) * FRAME_SIZE];
+                for (s, out) in input.iter().zip(self.frame.iter_mut().skip(c).step_by(CHANNELS)) {
+                    *out = *s;
+                }
+            }
+            let size = self.encoder.encode_float(&self.frame, &mut self.packet)?;
+            self.samples_out += FRAME_SIZE as u64;
+            packets.push(self.packet[..size].to_vec());
+        }
+        self.planar.iter_mut().for_each(|c| { c.drain(..frames * FRAME_SIZE); });
+        return Ok(packets);
+    }
+
+    // Split into channels, resampled to 48kHz
+    fn push(&mut self, buf: &[Samp], flush: bool) {
+        let before = self.planar[0].len();
+        for (c, channel) in self.planar.iter_mut().enumerate() {
+            let input: Vec<Samp> = buf.iter().skip(c).step_by(CHANNELS).copied().collect();
+            match &mut self.resamplers {
+                Some(resamplers) => {
+                    resamplers[c].process(&input, channel);
+                    if flush {
+                        resamplers[c].flush(channel);
+                    }
+                },
+                None => channel.extend(input)
+            }
+        }
+        self.samples_in += (self.planar[0].len() - before) as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use ogg::reading::PacketReader;
+
+    use super::*;
+    use crate::output::tests::{Memory, test_signal};
+    use crate::resample::resample;
+
+    #[test]
+    fn granules_cover_the_audio_and_no_more() {
+        let frames = 12345;
+        let memory = Memory::default();
+        let mut sink = Box::new(OpusSink::new(Box::new(memory.clone()), &[], 96000).unwrap());
+        for piece in test_signal(frames).chunks(1000 * CHANNELS) {
+            sink.write(piece).unwrap();
+        }
+        sink.finish().unwrap();
+
+        let mut reader = PacketReader::new(Cursor::new(memory.bytes()));
+        let head = reader.read_packet().unwrap().unwrap();
+        assert_eq!(&head.data[..8], b"OpusHead");
+        let pre_skip = u16::from_le_bytes([head.data[10], head.data[11]]) as u64;
+        assert_eq!(pre_skip, OpusEncoder::new(96000).unwrap().pre_skip);
+        let tags = reader.read_packet().unwrap().unwrap();
+        assert_eq!(&tags.data[..8], b"OpusTags");
+
+        let mut packets = 0;
+        let mut granules = Vec::new();
+        let mut ended = false;
+        while let Some(packet) = reader.read_packet().unwrap() {
+            packets += 1;
+            if packet.last_in_page() {
+                granules.push(packet.absgp_page());
+            }
+            ended = packet.last_in_stream();
+        }
+        assert!(ended);
+        assert!(granules.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", granules);
+
+        // The last granule is where the real audio ends once the pre-skip's dropped, and there
+        // are just enough packets to reach it
+        let samples = resample(&vec![0.0; frames], sample_rate().get(), OPUS_RATE).len() as u64;
+        let end = *granules.last().unwrap();
+        assert_eq!(end, pre_skip + samples);
+        assert_eq!(packets, (end + FRAME_SIZE as u64 - 1) / FRAME_SIZE as u64);
+    }
+}
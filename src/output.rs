@@ -0,0 +1,718 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Stdout, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{SyncSender, sync_channel};
+use std::thread::{JoinHandle, spawn};
+
+use clap::ValueEnum;
+
+use vorbis_rs::VorbisError;
+
+use crate::announce_channel::{MusicCue, Stands, Voice};
+use crate::events::{GameInfo, Hit};
+use crate::master::Master;
+use crate::pacing::Pacer;
+use crate::split_output::Split;
+use crate::types::{Samp, CHANNELS, sample_rate};
+use crate::{flac_output, opus_output, pcm_output, vorbis_output, wav_output};
+
+// Where the finished broadcast goes. Sinks take interleaved stereo from the master and encode it
+// however they like. Each one runs in its own thread with its own queue of blocks, so the mix is
+// only made once and a sink that's slow to encode or write doesn't hold up the rest, up to a
+// point: once a sink's MAX_QUEUE_SECONDS behind, the mix waits for it rather than lose audio or
+// hold the whole game in memory.
+
+pub const BLOCK_SIZE: usize = 1024;
+// How far a sink can fall behind the mix before we say something
+const BEHIND_WARNING_SECONDS: u64 = 30;
+// How far it can fall behind before the mix waits for it
+const MAX_QUEUE_SECONDS: u64 = 60;
+pub const ENCODER: &str = concat!("bladio ", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug)]
+pub enum OutputError {
+    IoError(io::Error),
+    VorbisError(VorbisError),
+    OpusError(audiopus::Error),
+}
+
+impl From<io::Error> for OutputError {
+    fn from(value: io::Error) -> Self {
+        return OutputError::IoError(value);
+    }
+}
+impl From<VorbisError> for OutputError {
+    fn from(value: VorbisError) -> Self {
+        return OutputError::VorbisError(value);
+    }
+}
+impl From<audiopus::Error> for OutputError {
+    fn from(value: audiopus::Error) -> Self {
+        return OutputError::OpusError(value);
+    }
+}
+
+impl fmt::Display for OutputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            OutputError::IoError(err) => write!(f, "{}", err),
+            OutputError::VorbisError(err) => write!(f, "Vorbis encoder: {}", err),
+            OutputError::OpusError(err) => write!(f, "Opus encoder: {}", err)
+        };
+    }
+}
+
+// Something that happens at a point in the broadcast, that sinks might want to know about
+#[derive(Debug, Clone)]
+pub enum Marker {
+    NowPlaying(String), // Describes the game as it stands, like the score
+    Line { voice: Voice, text: String, frames: u64 }, // Something said, marked where it ends
+    Sound(Sound), // Sound effect or music cue
+    Chapter(String), // Start of a part of the game, with its title
+}
+
+#[derive(Debug, Clone)]
+pub enum Sound {
+    Thwack(Hit),
+    Cheer(Stands),
+    Groan(Stands),
+    MusicStart(MusicCue),
+    MusicStop,
+}
+
+impl Sound {
+    pub fn kind(&self) -> &'static str {
+        return match self {
+            Sound::Thwack(_) => "thwack",
+            Sound::Cheer(_) => "cheer",
+            Sound::Groan(_) => "groan",
+            Sound::MusicStart(_) => "music_start",
+            Sound::MusicStop => "music_stop"
+        };
+    }
+
+    // Which one it was, where there's a choice
+    pub fn which(&self) -> &'static str {
+        return match self {
+            Sound::Thwack(hit) => hit.name(),
+            Sound::Cheer(stands) | Sound::Groan(stands) => stands.name(),
+            Sound::MusicStart(music) => music.name(),
+            Sound::MusicStop => ""
+        };
+    }
+}
+
+pub trait Sink {
+    fn write(&mut self, buf: &[Samp]) -> Result<(), OutputError>;
+
+    // Called with the frame a marker is at, before writing the block it falls in
+    fn mark(&mut self, _frame: u64, _marker: &Marker) -> Result<(), OutputError> {
+        return Ok(());
+    }
+
+    // Called once at the end, to flush the encoder and fill in anything it couldn't know up front
+    fn finish(self: Box<Self>) -> Result<(), OutputError>;
+}
+
+// Something to write encoded audio to. Files can go back and fix up their headers once the length
+// is known; pipes can't, so those headers get left as "unknown".
+pub trait Destination: Write {
+    // Overwrite bytes at an offset from the start. False if that's not possible.
+    fn rewrite(&mut self, offset: u64, bytes: &[u8]) -> io::Result<bool>;
+}
+
+impl Destination for File {
+    fn rewrite(&mut self, offset: u64, bytes: &[u8]) -> io::Result<bool> {
+        let end = self.stream_position()?;
+        self.seek(SeekFrom::Start(offset))?;
+        self.write_all(bytes)?;
+        self.seek(SeekFrom::Start(end))?;
+        return Ok(true);
+    }
+}
+
+impl Destination for Stdout {
+    fn rewrite(&mut self, _offset: u64, _bytes: &[u8]) -> io::Result<bool> {
+        return Ok(false);
+    }
+}
+
+// Encoder settings, which can be different for each output
+#[derive(Clone, Copy, Debug)]
+pub struct Settings {
+    pub quality: f32, // Vorbis, from -0.1 to 1
+    pub bitrate: u32, // Opus, in bits per second
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        return Settings {
+            quality: 0.5,
+            bitrate: 96000,
+        };
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum Format {
+    Vorbis, // Ogg Vorbis
+    Wav, // 16-bit PCM WAV
+    F32, // Raw interleaved 32-bit float PCM, little-endian
+    S16, // Raw interleaved 16-bit PCM, little-endian
+    Flac,
+    Opus, // Ogg Opus, at 48kHz
+}
+
+impl Format {
+    // Guess the format from a file name
+    pub fn from_path(path: &Path) -> Option<Format> {
+        return match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "ogg" | "oga" => Some(Format::Vorbis),
+            "wav" => Some(Format::Wav),
+            "f32" => Some(Format::F32),
+            "pcm" | "raw" | "s16" => Some(Format::S16),
+            "flac" => Some(Format::Flac),
+            "opus" => Some(Format::Opus),
+            _ => None
+        };
+    }
+
+    // The usual file extension, for naming files we make up
+    pub fn extension(self) -> &'static str {
+        return match self {
+            Format::Vorbis => "ogg",
+            Format::Wav => "wav",
+            Format::F32 => "f32",
+            Format::S16 => "raw",
+            Format::Flac => "flac",
+            Format::Opus => "opus",
+        };
+    }
+
+    // Comments are dropped by the formats that have nowhere to put them
+    pub fn sink(self, destination: Box<dyn Destination>, comments: &[(String, String)], settings: &Settings)
+        -> Result<Box<dyn Sink>, OutputError> {
+        return Ok(match self {
+            Format::Vorbis => Box::new(vorbis_output::VorbisSink::new(destination, comments, settings.quality)?),
+            Format::Wav => Box::new(wav_output::WavSink::new(destination)?),
+            Format::F32 => Box::new(pcm_output::PcmSink::new(destination, pcm_output::Encoding::F32)),
+            Format::S16 => Box::new(pcm_output::PcmSink::new(destination, pcm_output::Encoding::S16)),
+            Format::Flac => Box::new(flac_output::FlacSink::new(destination, comments)?),
+            Format::Opus => Box::new(opus_output::OpusSink::new(destination, comments, settings.bitrate)?),
+        });
+    }
+}
+
+// An output as given on the command line: a path, then any of format=, quality=, bitrate= (in
+// kbps) and split=, separated by commas. For example "game.ogg,quality=0.8" or "game.opus,bitrate=64".
+#[derive(Clone, Debug)]
+pub struct OutputSpec {
+    pub path: PathBuf,
+    pub format: Option<Format>,
+    pub settings: Settings,
+    pub split: Option<Split>,
+}
+
+impl FromStr for OutputSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let mut spec = OutputSpec {
+            path: PathBuf::from(parts.next().unwrap_or("-")),
+            format: None,
+            settings: Settings::default(),
+            split: None,
+        };
+        for part in parts {
+            let (key, value) = part.split_once('=').ok_or(format!("expected key=value, not {:?}", part))?;
+            match key {
+                "format" => spec.format = Some(Format::from_str(value, true)?),
+                "quality" => spec.settings.quality = parse_quality(value)?,
+                "bitrate" => spec.settings.bitrate = parse_bitrate(value)?,
+                "split" => spec.split = Some(value.parse()?),
+                _ => return Err(format!("unknown output setting {:?}", key))
+            }
+        }
+        return Ok(spec);
+    }
+}
+
+// Vorbis quality, from -0.1 to 1
+pub fn parse_quality(value: &str) -> Result<f32, String> {
+    return match value.parse::<f32>() {
+        Ok(quality) if (-0.1..=1.0).contains(&quality) => Ok(quality),
+        _ => Err("quality goes from -0.1 to 1".to_string())
+    };
+}
+
+// Opus bitrate given in kbps, returned in bits per second
+pub fn parse_bitrate(value: &str) -> Result<u32, String> {
+    return match value.parse::<u32>() {
+        Ok(kbps) if (6..=510).contains(&kbps) => Ok(kbps * 1000),
+        _ => Err("bitrate goes from 6 to 510 kbps".to_string())
+    };
+}
+
+// Vorbis comment fields describing the broadcast. Vorbis, Opus and FLAC all use these.
+pub fn comments(info: &GameInfo, station: &str) -> Vec<(String, String)> {
+    let mut comments = vec![
+        ("TITLE".to_string(), info.title.clone()),
+        ("ARTIST".to_string(), station.to_string()),
+    ];
+    let optional = [
+        ("DATE", info.date.clone()),
+        ("GAME_ID", info.game_id.clone()),
+        ("SEASON", info.season.map(|s| s.to_string())),
+        ("DAY", info.day.map(|d| d.to_string())),
+    ];
+    for (field, value) in optional {
+        if let Some(value) = value {
+            comments.push((field.to_string(), value));
+        }
+    }
+    comments.push(("ENCODER".to_string(), ENCODER.to_string()));
+    return comments;
+}
+
+// A comment header as Opus and FLAC store it: vendor string, then "FIELD=value" strings, each
+// with a little-endian length in front
+pub fn comment_header(comments: &[(String, String)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend((ENCODER.len() as u32).to_le_bytes());
+    out.extend(ENCODER.as_bytes());
+    out.extend((comments.len() as u32).to_le_bytes());
+    for (field, value) in comments {
+        let comment = format!("{}={}", field, value);
+        out.extend((comment.len() as u32).to_le_bytes());
+        out.extend(comment.as_bytes());
+    }
+    return out;
+}
+
+// Open a file to write to, or stdout if there's no path or it's "-"
+pub fn open(path: Option<&Path>) -> io::Result<Box<dyn Destination>> {
+    return match path {
+        Some(path) if path != Path::new("-") => Ok(Box::new(File::create(path)?)),
+        _ => Ok(Box::new(io::stdout()))
+    };
+}
+
+// A block of the mix, with the markers that go before it
+pub struct Block {
+    pub markers: Vec<(u64, Marker)>,
+    pub audio: Vec<Samp>,
+    pub end: bool, // The last of the broadcast
+}
+
+// A sink running in a thread of its own
+pub struct SinkThread {
+    name: String,
+    queue: Option<SyncSender<Arc<Block>>>, // None once the sink's given up
+    written: Arc<AtomicU64>, // Frames the sink's got through
+    behind: bool, // Whether we've warned that it's falling behind
+    thread: JoinHandle<Result<(), OutputError>>,
+}
+
+impl SinkThread {
+
+    // The sink's made in its thread, as encoders don't all like being moved between threads.
+    // Errors making it come back here, so they're reported before the broadcast starts.
+    pub fn spawn<F>(name: &str, make: F) -> Result<SinkThread, OutputError>
+        where F: FnOnce() -> Result<Box<dyn Sink>, OutputError> + Send + 'static {
+        let capacity = (MAX_QUEUE_SECONDS * sample_rate().get() as u64) as usize / BLOCK_SIZE;
+        let (tx, rx) = sync_channel::<Arc<Block>>(capacity);
+        let (ready_tx, ready_rx) = sync_channel(1);
+        let written = Arc::new(AtomicU64::new(0));
+        let progress = written.clone();
+        let thread = spawn(move || {
+            let mut sink = match make() {
+                Ok(sink) => sink,
+                Err(err) => {
+                    let _ = ready_tx.send(Err(err));
+                    return Ok(());
+                }
+            };
+            let _ = ready_tx.send(Ok(()));
+            // If the queue's closed without an end block, something's gone wrong upstream, but
+            // the sink still gets finished with what it has
+            for block in rx {
+                for (frame, marker) in block.markers.iter() {
+                    sink.mark(*frame, marker)?;
+                }
+                sink.write(&block.audio)?;
+                progress.fetch_add((block.audio.len() / CHANNELS) as u64, Ordering::Relaxed);
+                if block.end {
+                    break;
+                }
+            }
+            return sink.finish();
+        });
+        match ready_rx.recv() {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => return Err(err),
+            Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "sink thread panicked").into())
+        }
+        return Ok(SinkThread {
+            name: name.to_string(),
+            queue: Some(tx),
+            written: written,
+            behind: false,
+            thread: thread,
+        });
+    }
+
+    // Queue a block, which takes the mix up to frame. Waits if the queue's full.
+    fn send(&mut self, block: &Arc<Block>, frame: u64) {
+        if let Some(queue) = &self.queue {
+            if queue.send(block.clone()).is_err() {
+                // It's stopped with an error, which we'll hear about when it's joined
+                self.queue = None;
+                return;
+            }
+            let behind = frame - self.written.load(Ordering::Relaxed);
+            let rate = sample_rate().get() as u64;
+            if !self.behind && behind > BEHIND_WARNING_SECONDS * rate {
+                eprintln!("{} is falling behind, {}s queued", self.name, behind / rate);
+                self.behind = true;
+            } else if self.behind && behind < rate {
+                eprintln!("{} has caught up", self.name);
+                self.behind = false;
+            }
+        }
+    }
+
+    // Let the sink work through its queue and finish
+    fn finish(self) -> Result<(), OutputError> {
+        drop(self.queue);
+        return match self.thread.join() {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "sink thread panicked").into())
+        };
+    }
+}
+
+// Hands each block to all the sinks
+pub struct Fanout {
+    sinks: Vec<SinkThread>,
+    frame: u64, // Frames sent so far
+}
+
+impl Fanout {
+
+    pub fn new(sinks: Vec<SinkThread>) -> Fanout {
+        return Fanout {
+            sinks: sinks,
+            frame: 0,
+        };
+    }
+
+    pub fn send(&mut self, block: Block) {
+        self.frame += (block.audio.len() / CHANNELS) as u64;
+        let block = Arc::new(block);
+        for sink in self.sinks.iter_mut() {
+            sink.send(&block, self.frame);
+        }
+    }
+
+    // A sink that fails doesn't stop the others; its error is reported and the first one returned
+    // once they've all finished
+    pub fn finish(self) -> Result<(), OutputError> {
+        let mut result = Ok(());
+        for sink in self.sinks {
+            let name = sink.name.clone();
+            if let Err(err) = sink.finish() {
+                eprintln!("{}: {}", name, err);
+                if result.is_ok() {
+                    result = Err(err);
+                }
+            }
+        }
+        return result;
+    }
+}
+
+// Keep pulling audio from the master into the sinks until it says it's finished, which is once the
+// announcers have got to the end and everything's rung out. In real time, it goes through a pacer
+// to come out at the sample rate by the wall clock; otherwise as fast as it's made.
+pub fn output_to_sinks(master: &mut Master, sinks: Vec<SinkThread>, real_time: bool) -> Result<(), OutputError> {
+    let fanout = Fanout::new(sinks);
+    let mut route = if real_time { Route::Paced(Pacer::spawn(fanout)) } else { Route::Direct(fanout) };
+    let mut buf: [Samp; BLOCK_SIZE * CHANNELS] = [0.0; BLOCK_SIZE * CHANNELS];
+    loop {
+        let samples_filled = master.next(&mut buf);
+        let end = master.is_finished();
+        let block = Block {
+            markers: master.take_markers(),
+            audio: buf[..samples_filled].to_vec(),
+            end: end,
+        };
+        match &mut route {
+            Route::Direct(fanout) => fanout.send(block),
+            Route::Paced(pacer) => pacer.send(block)
+        }
+        if end {
+            break;
+        }
+    }
+    return match route {
+        Route::Direct(fanout) => fanout.finish(),
+        Route::Paced(pacer) => pacer.finish()
+    };
+}
+
+// Where blocks go from the master: straight to the sinks, or through a pacer
+enum Route {
+    Direct(Fanout),
+    Paced(Pacer),
+}
+
+// HH:MM:SS with milliseconds after the separator: a comma for SRT, a dot for WebVTT and chapters
+pub fn timestamp(frame: u64, separator: char) -> String {
+    let ms = frame * 1000 / sample_rate().get() as u64;
+    return format!("{:02}:{:02}:{:02}{}{:03}", ms / 3600000, ms / 60000 % 60, ms / 1000 % 60, separator, ms % 1000);
+}
+
+// Convert to 16-bit, clipping anything out of range
+pub fn to_i16(s: Samp) -> i16 {
+    return (s.clamp(-1.0, 1.0) * 32767.0).round() as i16;
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::fs;
+    use std::io::Cursor;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::{Receiver, channel};
+
+    use super::*;
+    use crate::announce_channel::{AnnounceChannel, AnnounceEvent};
+    use crate::effects::Preset;
+    use crate::master::MasterSettings;
+    use crate::mixer::{Ducking, Levels, Mixer};
+    use crate::sample_library::{Category, SampleLibrary, Selection, Selector};
+    use crate::tts::{Prosody, Synthesizer, TtsError};
+    use crate::{game_state, json_file_source};
+
+    // How long the stand-in announcers take over each line
+    const LINE_SECONDS: f32 = 0.05;
+
+    // An announcer that says every line as LINE_SECONDS of silence
+    #[derive(Default)]
+    struct StandIn {
+        left: usize
+    }
+
+    impl Synthesizer for StandIn {
+        fn is_speaking(&self) -> bool {
+            return self.left > 0;
+        }
+
+        fn prosody(&self) -> Prosody {
+            return Prosody::default();
+        }
+
+        fn say(&mut self, _message: &str, _prosody: Prosody) -> Result<(), TtsError> {
+            self.left = (LINE_SECONDS * sample_rate().get() as f32) as usize;
+            return Ok(());
+        }
+
+        fn say_ssml(&mut self, ssml: &str, prosody: Prosody) -> Result<(), TtsError> {
+            return self.say(ssml, prosody);
+        }
+
+        fn next(&mut self, buf: &mut [Samp]) -> usize {
+            let n = buf.len().min(self.left);
+            buf[..n].fill(0.0);
+            self.left -= n;
+            return n;
+        }
+    }
+
+    // Everything a sink was given
+    #[derive(Default)]
+    struct Capture {
+        frames: u64,
+        markers: Vec<(u64, Marker)>,
+        finished: bool,
+    }
+
+    struct CaptureSink(Arc<Mutex<Capture>>);
+
+    impl Sink for CaptureSink {
+        fn write(&mut self, buf: &[Samp]) -> Result<(), OutputError> {
+            self.0.lock().unwrap().frames += (buf.len() / CHANNELS) as u64;
+            return Ok(());
+        }
+
+        fn mark(&mut self, frame: u64, marker: &Marker) -> Result<(), OutputError> {
+            self.0.lock().unwrap().markers.push((frame, marker.clone()));
+            return Ok(());
+        }
+
+        fn finish(self: Box<Self>) -> Result<(), OutputError> {
+            self.0.lock().unwrap().finished = true;
+            return Ok(());
+        }
+    }
+
+    // Somewhere in memory to encode to, which can still be read once the sink's done with it
+    #[derive(Clone, Default)]
+    pub struct Memory(Arc<Mutex<Cursor<Vec<u8>>>>);
+
+    impl Memory {
+        pub fn bytes(&self) -> Vec<u8> {
+            return self.0.lock().unwrap().get_ref().clone();
+        }
+    }
+
+    impl Write for Memory {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            return self.0.lock().unwrap().write(buf);
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    impl Destination for Memory {
+        fn rewrite(&mut self, offset: u64, bytes: &[u8]) -> io::Result<bool> {
+            let mut cursor = self.0.lock().unwrap();
+            let end = cursor.position();
+            cursor.set_position(offset);
+            cursor.write_all(bytes)?;
+            cursor.set_position(end);
+            return Ok(true);
+        }
+    }
+
+    // Something for the encoders to chew on: a tone on the left and noise on the right, then
+    // silence, then a burst clipped past full scale
+    pub fn test_signal(frames: usize) -> Vec<Samp> {
+        fastrand::seed(1);
+        let rate = sample_rate().get() as f32;
+        let mut signal = Vec::with_capacity(frames * CHANNELS);
+        for i in 0..frames {
+            let (left, right) = match i * 4 / frames {
+                0 | 1 => ((i as f32 * 440.0 * std::f32::consts::TAU / rate).sin() * 0.5, fastrand::f32() - 0.5),
+                2 => (0.0, 0.0),
+                _ => (1.5, -1.5 * fastrand::f32())
+            };
+            signal.extend([left, right]);
+        }
+        return signal;
+    }
+
+    // Run announcer events through the mix and out to a capture sink, the way main does, with
+    // stand-in voices so it doesn't matter whether Mimic 3 is installed
+    fn broadcast(rx: Receiver<AnnounceEvent>, sfx: &SampleLibrary) -> Capture {
+        let announcer = AnnounceChannel::new(rx, sfx, Selector::new(Selection::AvoidRecent, 2),
+            Box::new(StandIn::default()), Box::new(StandIn::default()), false, None, false);
+        let mixer = Mixer::new(announcer, Levels { voice: 1.0, sfx: 1.0, crowd: 0.5, music: 0.6 },
+            Ducking { attack_ms: 60.0, release_ms: 500.0, depth_db: 9.0 },
+            Preset::Clean.effects(0.0), sfx.pick(Category::Ambience, &[]));
+        let mut master = Master::new(mixer, MasterSettings { target_lufs: Some(-16.0), ceiling_db: -1.0 });
+        let capture = Arc::new(Mutex::new(Capture::default()));
+        let sink = capture.clone();
+        let sinks = vec![SinkThread::spawn("capture", move || Ok(Box::new(CaptureSink(sink)))).unwrap()];
+        output_to_sinks(&mut master, sinks, false).unwrap();
+        let mut capture = capture.lock().unwrap();
+        return std::mem::take(&mut *capture);
+    }
+
+    // The events given, with the channel left open the way the game thread leaves it
+    fn broadcast_events(events: Vec<AnnounceEvent>) -> Capture {
+        let sfx = SampleLibrary::new(Path::new("no_sfx"));
+        let (tx, rx) = channel();
+        for event in events {
+            tx.send(event).unwrap();
+        }
+        let capture = broadcast(rx, &sfx);
+        drop(tx);
+        return capture;
+    }
+
+    fn chapters(capture: &Capture) -> Vec<(u64, String)> {
+        return capture.markers.iter().filter_map(|(frame, marker)| match marker {
+            Marker::Chapter(title) => Some((*frame, title.clone())),
+            _ => None
+        }).collect();
+    }
+
+    #[test]
+    fn stops_at_finish_even_with_the_channel_open() {
+        let events = |extra: u64| vec![
+            AnnounceEvent::Chapter("Start".to_string()),
+            AnnounceEvent::Delay(1000),
+            AnnounceEvent::Chapter("Middle".to_string()),
+            AnnounceEvent::Delay(BLOCK_SIZE as u64 * 5 + 7 + extra),
+            AnnounceEvent::Chapter("End".to_string()),
+            AnnounceEvent::Finish(),
+            AnnounceEvent::Chapter("After".to_string()),
+        ];
+        let short = broadcast_events(events(0));
+        let long = broadcast_events(events(3000));
+        assert!(short.finished && long.finished);
+
+        let marked = chapters(&short);
+        let titles: Vec<&str> = marked.iter().map(|(_, title)| title.as_str()).collect();
+        assert_eq!(titles, ["Start", "Middle", "End"]);
+        let (start, end) = (marked[0].0, marked[2].0);
+        assert_eq!(end - start, 1000 + BLOCK_SIZE as u64 * 5 + 7);
+        assert!(short.frames >= end);
+
+        // Everything after Finish is the same ring-out, so the lengths differ by just the extra wait
+        assert_eq!(long.frames - short.frames, 3000);
+    }
+
+    #[test]
+    fn tail_of_each_test_game_is_kept() {
+        let sfx = SampleLibrary::new(Path::new("sfx"));
+        for entry in fs::read_dir("test_data/games").unwrap() {
+            let path = entry.unwrap().path();
+            let (game_tx, game_rx) = channel();
+            let (announce_tx, announce_rx) = channel();
+            let source = json_file_source::new(json_file_source::load(path.to_str().unwrap()).unwrap(), game_tx);
+            let game = game_state::spawn_game_thread(game_rx, announce_tx, None);
+            let capture = broadcast(announce_rx, &sfx);
+            source.join().unwrap();
+            game.join().unwrap();
+
+            assert!(capture.finished, "{:?} wasn't finished", path);
+            let last = capture.markers.iter().map(|(frame, _)| *frame).max().unwrap();
+            assert!(capture.frames >= last, "{:?} cut off at {} before its last marker at {}", path, capture.frames, last);
+            // The last line is said right through
+            let last_line = capture.markers.iter().rev().find_map(|(frame, marker)| match marker {
+                Marker::Line { frames, .. } => Some((*frame, *frames)),
+                _ => None
+            });
+            let (end, frames) = last_line.expect("no lines were said");
+            assert!(frames >= (LINE_SECONDS * sample_rate().get() as f32) as u64, "{:?} cut its last line short", path);
+            assert!(capture.frames >= end, "{:?} cut off during its last line", path);
+            assert_eq!(chapters(&capture).last().map(|(_, title)| title.as_str()), Some("Postgame"), "{:?}", path);
+            let final_score = capture.markers.iter().rev().find_map(|(_, marker)| match marker {
+                Marker::NowPlaying(text) => Some(text.clone()),
+                _ => None
+            });
+            assert!(final_score.map_or(false, |text| text.starts_with("Final")), "{:?} has no final score", path);
+        }
+    }
+
+    #[test]
+    fn live_channel_fills_in_while_idle() {
+        let sfx = SampleLibrary::new(Path::new("no_sfx"));
+        let (_tx, rx) = channel::<AnnounceEvent>();
+        let mut announcer = AnnounceChannel::new(rx, &sfx, Selector::new(Selection::AvoidRecent, 2),
+            Box::new(StandIn::default()), Box::new(StandIn::default()), false, None, true);
+        let mut buf = [1.0; BLOCK_SIZE];
+        assert_eq!(announcer.next(&mut buf), BLOCK_SIZE);
+        assert!(buf.iter().all(|s| *s == 0.0));
+        assert!(!announcer.is_finished());
+    }
+}
@@ -0,0 +1,159 @@
+use std::sync::mpsc::{Receiver, SyncSender, TryRecvError, sync_channel};
+use std::thread::{JoinHandle, sleep, spawn};
+use std::time::{Duration, Instant};
+
+use crate::output::{Block, Fanout, OutputError, BLOCK_SIZE};
+use crate::types::{Samp, CHANNELS, sample_rate, ms_to_samples};
+
+// Sends the mix on to the sinks at the sample rate by the wall clock, for live listening. The mix
+// is made up to LEAD_SECONDS ahead. When it's fallen behind, say while a long line's being
+// synthesised, a block of silence goes out in its place rather than the stream stalling, and
+// markers after it move along to match. Blocks are held back by one so the audio either side of
+// the silence can be faded out and back in, rather than clicking. Underruns and how late the
+// clock's running are reported for each minute they happen in, and in total at the end.
+
+const LEAD_SECONDS: usize = 5;
+const REPORT_SECONDS: u64 = 60;
+// Lateness worth mentioning even without underruns
+const LATE_WARNING_MS: u128 = 50;
+// Fade either side of an underrun
+const FADE_MS: u64 = 10;
+
+pub struct Pacer {
+    queue: SyncSender<Block>,
+    thread: JoinHandle<Result<(), OutputError>>,
+}
+
+impl Pacer {
+
+    pub fn spawn(fanout: Fanout) -> Pacer {
+        let (tx, rx) = sync_channel(LEAD_SECONDS * sample_rate().get() as usize / BLOCK_SIZE);
+        return Pacer {
+            queue: tx,
+            thread: spawn(move || pace(rx, fanout)),
+        };
+    }
+
+    // Waits while the mix is as far ahead as it's allowed to get
+    pub fn send(&self, block: Block) {
+        // If the pacer's gone, finish() will say why
+        let _ = self.queue.send(block);
+    }
+
+    // Play out what's queued and finish the sinks
+    pub fn finish(self) -> Result<(), OutputError> {
+        drop(self.queue);
+        return match self.thread.join() {
+            Ok(result) => result,
+            Err(_) => Err(std::io::Error::new(std::io::ErrorKind::Other, "pacer thread panicked").into())
+        };
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    underruns: u64, // Times the mix wasn't ready
+    filler: u64, // Frames of silence sent in its place
+    late: Duration, // Furthest behind the wall clock we sent a block
+}
+
+impl Stats {
+    fn add(&mut self, other: &Stats) {
+        self.underruns += other.underruns;
+        self.filler += other.filler;
+        self.late = self.late.max(other.late);
+    }
+
+    fn report(&self, when: &str) {
+        eprintln!("{}: {} underruns, {:.1}s of silence filled in, up to {}ms late", when, self.underruns,
+            self.filler as f64 / sample_rate().get() as f64, self.late.as_millis());
+    }
+}
+
+fn pace(rx: Receiver<Block>, mut fanout: Fanout) -> Result<(), OutputError> {
+    let rate = sample_rate().get() as u64;
+    // The clock starts once there's something to play
+    let mut next = match rx.recv() {
+        Ok(block) => Some(block),
+        Err(_) => return fanout.finish()
+    };
+    let start = Instant::now();
+    let mut sent = 0; // Frames, filler included
+    let mut filler = 0; // Frames of silence put in so far, which later markers are shifted by
+    let mut in_underrun = false;
+    let mut held: Option<Block> = None; // Goes out once we know whether silence comes after it
+    let mut minute = Stats::default();
+    let mut total = Stats::default();
+    let mut report_at = REPORT_SECONDS * rate;
+    loop {
+        let due = start + Duration::from_secs_f64(sent as f64 / rate as f64);
+        let now = Instant::now();
+        if due > now {
+            sleep(due - now);
+        } else {
+            minute.late = minute.late.max(now - due);
+        }
+
+        let mut block = match next.take().map_or_else(|| rx.try_recv(), Ok) {
+            Ok(mut block) => {
+                if in_underrun {
+                    fade(&mut block.audio, false);
+                }
+                in_underrun = false;
+                block
+            },
+            Err(TryRecvError::Empty) => {
+                if !in_underrun {
+                    minute.underruns += 1;
+                    in_underrun = true;
+                    if let Some(last) = held.as_mut() {
+                        fade(&mut last.audio, true);
+                    }
+                }
+                minute.filler += BLOCK_SIZE as u64;
+                filler += BLOCK_SIZE as u64;
+                Block { markers: Vec::new(), audio: vec![0.0; BLOCK_SIZE * CHANNELS], end: false }
+            },
+            Err(TryRecvError::Disconnected) => break
+        };
+        for (frame, _) in block.markers.iter_mut() {
+            *frame += filler;
+        }
+        sent += (block.audio.len() / CHANNELS) as u64;
+        let end = block.end;
+        if let Some(last) = held.replace(block) {
+            fanout.send(last);
+        }
+        if end {
+            break;
+        }
+
+        if sent >= report_at {
+            if minute.underruns > 0 || minute.late.as_millis() > LATE_WARNING_MS {
+                minute.report(&format!("Real time, minute {}", report_at / rate / REPORT_SECONDS));
+            }
+            total.add(&minute);
+            minute = Stats::default();
+            report_at += REPORT_SECONDS * rate;
+        }
+    }
+    if let Some(last) = held {
+        fanout.send(last);
+    }
+    total.add(&minute);
+    total.report("Real time, overall");
+    return fanout.finish();
+}
+
+// Ramp the start of the audio up from silence, or the end of it down to silence
+fn fade(audio: &mut [Samp], out: bool) {
+    let frames = audio.len() / CHANNELS;
+    let length = (ms_to_samples(FADE_MS) as usize).min(frames);
+    for i in 0..length {
+        let frame = if out { frames - 1 - i } else { i };
+        let gain = i as f32 / length as f32;
+        for sample in audio[frame * CHANNELS..(frame + 1) * CHANNELS].iter_mut() {
+            *sample *= gain;
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use crate::output::{Destination, OutputError, Sink, to_i16};
+use crate::types::Samp;
+
+// Headerless interleaved stereo, for piping into other tools
+pub enum Encoding {
+    F32,
+    S16,
+}
+
+pub struct PcmSink {
+    destination: Box<dyn Destination>,
+    encoding: Encoding,
+    bytes: Vec<u8>,
+}
+
+impl PcmSink {
+
+    pub fn new(destination: Box<dyn Destination>, encoding: Encoding) -> Self {
+        return PcmSink {
+            destination: destination,
+            encoding: encoding,
+            bytes: Vec::new(),
+        };
+    }
+}
+
+impl Sink for PcmSink {
+    fn write(&mut self, buf: &[Samp]) -> Result<(), OutputError> {
+        self.bytes.clear();
+        match self.encoding {
+            Encoding::F32 => buf.iter().for_each(|s| self.bytes.extend(s.to_le_bytes())),
+            Encoding::S16 => buf.iter().for_each(|s| self.bytes.extend(to_i16(*s).to_le_bytes())),
+        }
+        self.destination.write_all(&self.bytes)?;
+        return Ok(());
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), OutputError> {
+        self.destination.flush()?;
+        return Ok(());
+    }
+}
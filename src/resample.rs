@@ -0,0 +1,174 @@
+use std::f64::consts::PI;
+
+use crate::types::Samp;
+
+// Kaiser-windowed sinc resampler. The filter is tabulated once, then looked up with linear
+// interpolation, so any pair of rates works without a polyphase bank per ratio.
+
+const ZERO_CROSSINGS: usize = 32; // Filter reach either side, in zero crossings
+const KAISER_BETA: f64 = 8.6; // ~90dB stopband
+const ROLLOFF: f64 = 0.96; // Cutoff as a fraction of the lower Nyquist frequency
+const TABLE_RESOLUTION: usize = 512; // Table entries per input sample
+
+pub struct Resampler {
+    step: f64, // Input samples per output sample
+    half_width: f64, // Filter reach, in input samples
+    table: Vec<f32>,
+    pending: Vec<Samp>, // Input we still need to look at
+    time: f64, // Position of the next output sample in pending
+    input_end: f64, // Position just past the last real input sample in pending
+}
+
+impl Resampler {
+
+    pub fn new(from: u32, to: u32) -> Self {
+        let step = from as f64 / to as f64;
+        let cutoff = (1.0 / step).min(1.0) * ROLLOFF;
+        let half_width = ZERO_CROSSINGS as f64 / cutoff;
+
+        let table_len = (half_width * TABLE_RESOLUTION as f64).ceil() as usize + 2;
+        let table = (0..table_len).map(|i| {
+            let x = i as f64 / TABLE_RESOLUTION as f64;
+            if x >= half_width {
+                0.0
+            } else {
+                (cutoff * sinc(cutoff * x) * kaiser(x / half_width)) as f32
+            }
+        }).collect();
+
+        // Pretend there was silence before the first sample
+        let lead_in = half_width.ceil() as usize;
+        return Resampler {
+            step: step,
+            half_width: half_width,
+            table: table,
+            pending: vec![0.0; lead_in],
+            time: lead_in as f64,
+            input_end: lead_in as f64,
+        };
+    }
+
+    pub fn process(&mut self, input: &[Samp], output: &mut Vec<Samp>) {
+        self.pending.extend_from_slice(input);
+        self.input_end += input.len() as f64;
+        while self.time + self.half_width < self.pending.len() as f64 {
+            output.push(self.sample_at(self.time));
+            self.time += self.step;
+        }
+        // Drop input that's out of reach of every future output sample
+        let consumed = (self.time - self.half_width).floor().max(0.0) as usize;
+        self.pending.drain(..consumed);
+        self.time -= consumed as f64;
+        self.input_end -= consumed as f64;
+    }
+
+    // Push out the rest, as if the input were followed by silence
+    pub fn flush(&mut self, output: &mut Vec<Samp>) {
+        self.pending.resize(self.pending.len() + self.half_width.ceil() as usize + 1, 0.0);
+        while self.time < self.input_end {
+            output.push(self.sample_at(self.time));
+            self.time += self.step;
+        }
+        let lead_in = self.half_width.ceil() as usize;
+        self.pending.clear();
+        self.pending.resize(lead_in, 0.0);
+        self.time = lead_in as f64;
+        self.input_end = lead_in as f64;
+    }
+
+    fn sample_at(&self, t: f64) -> Samp {
+        let first = (t - self.half_width).ceil().max(0.0) as usize;
+        let last = ((t + self.half_width).floor() as usize).min(self.pending.len() - 1);
+        let mut acc: Samp = 0.0;
+        for i in first..=last {
+            acc += self.pending[i] * self.filter((i as f64 - t).abs());
+        }
+        return acc;
+    }
+
+    fn filter(&self, x: f64) -> f32 {
+        let pos = x * TABLE_RESOLUTION as f64;
+        let i = pos as usize;
+        if i + 1 >= self.table.len() {
+            return 0.0;
+        }
+        let frac = (pos - i as f64) as f32;
+        return self.table[i] + (self.table[i + 1] - self.table[i]) * frac;
+    }
+}
+
+// Resample a whole clip in one go
+pub fn resample(input: &[Samp], from: u32, to: u32) -> Vec<Samp> {
+    if from == to {
+        return input.to_vec();
+    }
+    let mut resampler = Resampler::new(from, to);
+    let mut output = Vec::with_capacity((input.len() as f64 * to as f64 / from as f64) as usize + 1);
+    resampler.process(input, &mut output);
+    resampler.flush(&mut output);
+    return output;
+}
+
+// Convert between channel layouts. Down-mixing to mono averages, up-mixing from mono copies,
+// anything else maps channels round-robin.
+pub fn remix(channels: Vec<Vec<Samp>>, to: usize) -> Vec<Vec<Samp>> {
+    let from = channels.len();
+    if from == to || from == 0 {
+        return channels;
+    } else if to == 1 {
+        let len = channels.iter().map(|c| c.len()).min().unwrap_or(0);
+        let mono = (0..len).map(|i| {
+            channels.iter().map(|c| c[i]).sum::<Samp>() / from as Samp
+        }).collect();
+        return vec![mono];
+    } else {
+        return (0..to).map(|c| channels[c % from].clone()).collect();
+    }
+}
+
+// Format tags, as in a WAV file's fmt chunk
+pub const FORMAT_PCM: u16 = 1;
+pub const FORMAT_FLOAT: u16 = 3;
+
+// Split interleaved little-endian samples into channels, or None if they're in a format we can't read
+pub fn decode_pcm(bytes: &[u8], format: u16, width: usize, channels: usize) -> Option<Vec<Vec<Samp>>> {
+    if channels == 0 {
+        return None;
+    }
+    let samples: Vec<Samp> = match (format, width) {
+        (FORMAT_PCM, 1) => bytes.iter().map(|b| (*b as Samp - 128.0) / 128.0).collect(),
+        (FORMAT_PCM, 2) => bytes.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]]) as Samp / 32768.0).collect(),
+        (FORMAT_PCM, 3) => bytes.chunks_exact(3).map(|b| i32::from_le_bytes([0, b[0], b[1], b[2]]) as Samp / 2147483648.0).collect(),
+        (FORMAT_PCM, 4) => bytes.chunks_exact(4).map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as Samp / 2147483648.0).collect(),
+        (FORMAT_FLOAT, 4) => bytes.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect(),
+        _ => return None
+    };
+    return Some((0..channels).map(|c| samples.iter().skip(c).step_by(channels).copied().collect()).collect());
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-9 {
+        return 1.0;
+    }
+    return (PI * x).sin() / (PI * x);
+}
+
+// Kaiser window, for x in -1..1
+fn kaiser(x: f64) -> f64 {
+    return bessel_i0(KAISER_BETA * (1.0 - x * x).max(0.0).sqrt()) / bessel_i0(KAISER_BETA);
+}
+
+// Zeroth order modified Bessel function of the first kind
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half_x = x / 2.0;
+    for k in 1..50 {
+        term *= (half_x / k as f64) * (half_x / k as f64);
+        sum += term;
+        if term < sum * 1e-12 {
+            break;
+        }
+    }
+    return sum;
+}
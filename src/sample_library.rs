@@ -1,16 +1,20 @@
+use std::collections::{HashMap, VecDeque};
 use std::io;
-use std::path::Path;
-use std::fs::{self, DirEntry, File};
+use std::iter::zip;
+use std::path::{Path, PathBuf};
+use std::fs::{self, File};
 
-use nonzero_ext::nonzero;
+use serde::Deserialize;
 use vorbis_rs::{VorbisError, VorbisDecoder};
 
-use crate::types::{Samp, SAMPLE_RATE};
+use crate::resample::{resample, remix, decode_pcm};
+use crate::types::{Samp, CHANNELS, sample_rate};
 
 #[derive(Debug)]
 enum SampleError {
     VorbisError(VorbisError),
     IoError(io::Error),
+    JsonError(serde_json::Error),
     BladioError(String)
 }
 
@@ -24,73 +28,398 @@ impl From<io::Error> for SampleError {
         return SampleError::IoError(value);
     }
 }
+impl From<serde_json::Error> for SampleError {
+    fn from(value: serde_json::Error) -> Self {
+        return SampleError::JsonError(value);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Category {
+    Thwack,
+    Foul,
+    CrowdCheer,
+    CrowdGroan,
+    Organ,
+    Jingle,
+    Ambience
+}
+
+const CATEGORIES: [Category; 7] = [
+    Category::Thwack, Category::Foul, Category::CrowdCheer, Category::CrowdGroan,
+    Category::Organ, Category::Jingle, Category::Ambience
+];
+
+impl Category {
+    // Also the name of the category's directory
+    pub fn name(self) -> &'static str {
+        return match self {
+            Category::Thwack => "thwack",
+            Category::Foul => "foul",
+            Category::CrowdCheer => "crowd_cheer",
+            Category::CrowdGroan => "crowd_groan",
+            Category::Organ => "organ",
+            Category::Jingle => "jingle",
+            Category::Ambience => "ambience"
+        };
+    }
+
+    fn from_name(name: &str) -> Option<Category> {
+        return CATEGORIES.iter().copied().find(|c| c.name() == name);
+    }
+}
+
+// Tags for how big a sound is. Samples without one get one from how loud they are.
+pub const INTENSITIES: [&str; 3] = ["soft", "medium", "loud"];
+
+pub struct Sample {
+    pub audio: Vec<Vec<Samp>>, // Mono or stereo
+    pub gain: f32,
+    pub loop_points: Option<(usize, usize)>, // Start and end of the looping part, in samples
+    pub tags: Vec<String>,
+    pub weight: f32, // How often it's picked, relative to the rest of its category
+}
+
+impl Sample {
+    // Length in frames
+    pub fn len(&self) -> usize {
+        return self.audio.first().map_or(0, |c| c.len());
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        return self.tags.iter().any(|t| t == tag);
+    }
+}
+
+// Entry in the manifest. Files are given relative to the library, like "thwack/thwack0.ogg".
+#[derive(Deserialize)]
+struct ManifestEntry {
+    category: Option<String>, // Defaults to the directory the file is in
+    gain: Option<f32>,
+    loop_start: Option<f32>, // Seconds
+    loop_end: Option<f32>,
+    #[serde(default)]
+    tags: Vec<String>,
+    weight: Option<f32>,
+}
 
+const MANIFEST_NAME: &str = "manifest.json";
 
+// Sound effects, sorted into categories. Each category is a subdirectory of the library;
+// manifest.json can add metadata, or pull in files from anywhere else.
 pub struct SampleLibrary {
-    samples: Vec<Vec<Samp>>
+    categories: HashMap<Category, Vec<Sample>>
 }
 
 impl SampleLibrary {
 
     pub fn new(sfx_path: &Path) -> Self {
-        let mut samples = Vec::new();
-        for entry in fs::read_dir(sfx_path).unwrap() {
-            match entry {
-                Ok(f) => {
-                    let os_name = f.file_name();
-                    let clean_name = os_name.to_str();
-                    let is_ogg = match clean_name {
-                        Some(n) => n.ends_with(".ogg"),
-                        _ => false
+        let mut library = SampleLibrary {
+            categories: HashMap::new()
+        };
+        // Where each file ended up, so the manifest can find it
+        let mut loaded: HashMap<PathBuf, (Category, usize)> = HashMap::new();
+
+        for category in CATEGORIES {
+            let dir = sfx_path.join(category.name());
+            let mut files: Vec<PathBuf> = match fs::read_dir(&dir) {
+                Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+                Err(_) => continue
+            };
+            files.sort();
+            for file in files {
+                match load_file(&file) {
+                    Some(Ok(audio)) => {
+                        let relative = file.strip_prefix(sfx_path).unwrap_or(&file).to_path_buf();
+                        loaded.insert(relative, (category, library.len(category)));
+                        library.add(category, audio);
+                    },
+                    Some(Err(err)) => eprintln!("{:?}", err),
+                    None => ()
+                }
+            }
+        }
+
+        if let Err(err) = library.apply_manifest(sfx_path, &mut loaded) {
+            eprintln!("Error reading {}: {:?}", MANIFEST_NAME, err);
+        }
+        library.tag_intensities();
+        return library;
+    }
+
+    fn add(&mut self, category: Category, audio: Vec<Vec<Samp>>) {
+        self.categories.entry(category).or_default().push(Sample {
+            audio: audio,
+            gain: 1.0,
+            loop_points: None,
+            tags: Vec::new(),
+            weight: 1.0
+        });
+    }
+
+    fn apply_manifest(&mut self, sfx_path: &Path, loaded: &mut HashMap<PathBuf, (Category, usize)>) -> Result<(), SampleError> {
+        let manifest_path = sfx_path.join(MANIFEST_NAME);
+        if !manifest_path.exists() {
+            return Ok(());
+        }
+        let mut manifest: Vec<(String, ManifestEntry)> =
+            serde_json::from_reader::<_, HashMap<String, ManifestEntry>>(File::open(manifest_path)?)?
+            .into_iter().collect();
+        manifest.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for (name, entry) in manifest {
+            let path = PathBuf::from(&name);
+            let (category, index) = match loaded.get(&path) {
+                Some(found) => *found,
+                None => {
+                    // Not in a category directory, so load it now
+                    let dir_name = path.parent().and_then(|p| p.file_name()).and_then(|n| n.to_str());
+                    let category = match entry.category.as_deref().or(dir_name).and_then(Category::from_name) {
+                        Some(category) => category,
+                        None => {
+                            eprintln!("No category for {}", name);
+                            continue;
+                        }
                     };
-                    if is_ogg {
-                        load_ogg(f).map_or_else( |err| {
-                            eprintln!("{:?}", err)
-                        },
-                        |s| {
-                            samples.push(s)
-                        });
+                    match load_file(&sfx_path.join(&path)) {
+                        Some(Ok(audio)) => self.add(category, audio),
+                        Some(Err(err)) => { eprintln!("{:?}", err); continue; },
+                        None => { eprintln!("Unknown file type: {}", name); continue; }
                     }
-                },
-                Err(e) => eprintln!("{}", e)
+                    let found = (category, self.len(category) - 1);
+                    loaded.insert(path, found);
+                    found
+                }
             };
+
+            let sample = &mut self.categories.get_mut(&category).unwrap()[index];
+            let rate = sample_rate().get() as f32;
+            sample.gain = entry.gain.unwrap_or(sample.gain);
+            sample.weight = entry.weight.unwrap_or(sample.weight).max(0.0);
+            sample.tags.extend(entry.tags);
+            if entry.loop_start.is_some() || entry.loop_end.is_some() {
+                let len = sample.len();
+                let start = entry.loop_start.map_or(0, |s| ((s * rate) as usize).min(len));
+                let end = entry.loop_end.map_or(len, |s| ((s * rate) as usize).min(len));
+                if start < end {
+                    sample.loop_points = Some((start, end));
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    // Give anything without an intensity tag one, by where it falls in its category's loudness
+    fn tag_intensities(&mut self) {
+        for samples in self.categories.values_mut() {
+            let mut levels: Vec<(usize, f32)> = samples.iter()
+                .map(|s| s.audio.iter().flatten().map(|x| x * x).sum::<f32>() / (s.len() * s.audio.len()).max(1) as f32 * s.gain * s.gain)
+                .enumerate()
+                .collect();
+            levels.sort_by(|a, b| a.1.total_cmp(&b.1));
+            let count = levels.len();
+            for (rank, (i, _)) in levels.into_iter().enumerate() {
+                let sample = &mut samples[i];
+                if !INTENSITIES.iter().any(|t| sample.has_tag(t)) {
+                    sample.tags.push(INTENSITIES[rank * INTENSITIES.len() / count].to_string());
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, category: Category) -> &[Sample] {
+        return self.categories.get(&category).map_or(&[], |s| s.as_slice());
+    }
+
+    pub fn len(&self, category: Category) -> usize {
+        return self.get(category).len();
+    }
+
+    // A random sample from the category, for one-off choices. Use a Selector for anything that
+    // gets picked over and over.
+    pub fn pick(&self, category: Category, tags: &[&str]) -> Option<&Sample> {
+        let samples = self.get(category);
+        return pick_weighted(samples, &self.matching(category, tags)).map(|i| &samples[i]);
+    }
+
+    // Whether anything in the category has all the given tags
+    pub fn has_tagged(&self, category: Category, tags: &[&str]) -> bool {
+        return self.get(category).iter().any(|s| tags.iter().all(|t| s.has_tag(t)));
+    }
+
+    // Samples in the category with all the given tags, like a loud hit. Falls back to anything
+    // in the category if none of them match.
+    fn matching(&self, category: Category, tags: &[&str]) -> Vec<usize> {
+        let samples = self.get(category);
+        let usable = |i: &usize| samples[*i].weight > 0.0;
+        let matching: Vec<usize> = (0..samples.len())
+            .filter(usable)
+            .filter(|i| tags.iter().all(|t| samples[*i].has_tag(t)))
+            .collect();
+        if matching.is_empty() {
+            return (0..samples.len()).filter(usable).collect();
         }
-        return SampleLibrary {
-            samples: samples,
+        return matching;
+    }
+
+}
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum Selection {
+    Weighted, // Independent weighted picks, so repeats happen
+    ShuffleBag, // Go through every sample before using any again
+    AvoidRecent, // Weighted picks, skipping the last few
+}
+
+// Picks samples from the library, remembering what it picked before
+pub struct Selector {
+    selection: Selection,
+    avoid_recent: usize,
+    bags: HashMap<Category, Vec<usize>>,
+    recent: HashMap<Category, VecDeque<usize>>,
+}
+
+impl Selector {
+
+    pub fn new(selection: Selection, avoid_recent: usize) -> Self {
+        return Selector {
+            selection: selection,
+            avoid_recent: avoid_recent,
+            bags: HashMap::new(),
+            recent: HashMap::new(),
         };
     }
 
-    pub fn get(& self, i: usize) -> &[Samp] {
-        return self.samples[i].as_slice();
+    pub fn pick<'a>(&mut self, library: &'a SampleLibrary, category: Category, tags: &[&str]) -> Option<&'a Sample> {
+        let samples = library.get(category);
+        let candidates = library.matching(category, tags);
+        let last = self.recent.get(&category).and_then(|r| r.back().copied());
+
+        let choice = match self.selection {
+            Selection::Weighted => pick_weighted(samples, &candidates),
+            Selection::ShuffleBag => {
+                let bag = self.bags.entry(category).or_default();
+                let mut found = bag.iter().position(|i| candidates.contains(i));
+                if found.is_none() {
+                    // Each sample goes in the bag as many times as its weight
+                    bag.clear();
+                    for i in (0..samples.len()).filter(|i| samples[*i].weight > 0.0) {
+                        let copies = samples[i].weight.round().max(1.0) as usize;
+                        bag.extend(std::iter::repeat(i).take(copies));
+                    }
+                    fastrand::shuffle(bag);
+                    // Don't start the new bag with what ended the last one
+                    found = bag.iter().position(|i| candidates.contains(i) && Some(*i) != last)
+                        .or_else(|| bag.iter().position(|i| candidates.contains(i)));
+                }
+                found.map(|pos| bag.remove(pos))
+            },
+            Selection::AvoidRecent => {
+                let recent = self.recent.get(&category);
+                let fresh: Vec<usize> = candidates.iter().copied()
+                    .filter(|i| !recent.map_or(false, |r| r.contains(i)))
+                    .collect();
+                pick_weighted(samples, if fresh.is_empty() { &candidates } else { &fresh })
+            }
+        };
+
+        if let Some(i) = choice {
+            let recent = self.recent.entry(category).or_default();
+            recent.push_back(i);
+            while recent.len() > self.avoid_recent.max(1) {
+                recent.pop_front();
+            }
+        }
+        return choice.map(|i| &samples[i]);
     }
+}
 
-    pub fn len(&self) -> usize {
-        return self.samples.len();
+// Index of one of the candidates, picked by weight
+fn pick_weighted(samples: &[Sample], candidates: &[usize]) -> Option<usize> {
+    let total: f32 = candidates.iter().map(|i| samples[*i].weight).sum();
+    if total <= 0.0 {
+        return None;
+    }
+    let mut choice = fastrand::f32() * total;
+    for i in candidates {
+        choice -= samples[*i].weight;
+        if choice < 0.0 {
+            return Some(*i);
+        }
     }
+    return candidates.last().copied();
+}
 
+fn load_file(path: &Path) -> Option<Result<Vec<Vec<Samp>>, SampleError>> {
+    return match path.extension().and_then(|e| e.to_str()) {
+        Some("ogg") => Some(load_ogg(path)),
+        Some("wav") => Some(load_wav(path)),
+        _ => None
+    };
 }
 
-fn load_ogg(file: DirEntry) -> Result<Vec<Samp>, SampleError> {
-    let reader = File::open(file.path())?;
+// Load a file at whatever rate and channel count, and convert it to mono or stereo at the output rate
+fn load_ogg(path: &Path) -> Result<Vec<Vec<Samp>>, SampleError> {
+    let reader = File::open(path)?;
     let mut vdec = VorbisDecoder::new(reader)?;
+    let rate = vdec.sampling_frequency().get();
 
-    if vdec.channels() != nonzero!(1u8) {
-        let err = format!("Error loading {:?}: too many channels.", file.file_name());
-        return Err(SampleError::BladioError(err));
-    } else if vdec.sampling_frequency() != SAMPLE_RATE {
-        let err = format!("Error loading {:?}: incorrect sample rate.", file.file_name());
-        return Err(SampleError::BladioError(err));
-    } else {
-        let mut buf: Vec<Samp> = Vec::new();
-        loop {
-            let res = vdec.decode_audio_block()?;
-            match res {
-                None => break,
-                Some(block) => {
-                    buf.extend_from_slice( block.samples()[0] );
+    let mut channels: Vec<Vec<Samp>> = vec![Vec::new(); vdec.channels().get() as usize];
+    loop {
+        let res = vdec.decode_audio_block()?;
+        match res {
+            None => break,
+            Some(block) => {
+                for (channel, samples) in zip(&mut channels, block.samples()) {
+                    channel.extend_from_slice(samples);
                 }
             }
         }
-        return Ok(buf);
     }
+
+    return Ok(conform(channels, rate));
+}
+
+// Load an uncompressed PCM or float WAV file
+fn load_wav(path: &Path) -> Result<Vec<Vec<Samp>>, SampleError> {
+    let data = fs::read(path)?;
+    let bad = |why: &str| SampleError::BladioError(format!("Error loading {:?}: {}", path, why));
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return Err(bad("not a WAV file"));
+    }
+
+    let mut format: Option<(u16, usize, u32, usize)> = None; // Format tag, channels, rate, bits
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let id = &data[pos..pos + 4];
+        let size = u32::from_le_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]]) as usize;
+        let body = &data[pos + 8..(pos + 8 + size).min(data.len())];
+        if id == b"fmt " && body.len() >= 16 {
+            format = Some((
+                u16::from_le_bytes([body[0], body[1]]),
+                u16::from_le_bytes([body[2], body[3]]) as usize,
+                u32::from_le_bytes([body[4], body[5], body[6], body[7]]),
+                u16::from_le_bytes([body[14], body[15]]) as usize,
+            ));
+        } else if id == b"data" {
+            let (tag, channel_count, rate, bits) = format.ok_or_else(|| bad("data before fmt chunk"))?;
+            if channel_count == 0 {
+                return Err(bad("no channels"));
+            }
+            let width = if bits % 8 == 0 { bits / 8 } else { 0 };
+            let channels = decode_pcm(body, tag, width, channel_count).ok_or_else(|| bad("unsupported sample format"))?;
+            return Ok(conform(channels, rate));
+        }
+        // Chunks are padded to an even length
+        pos += 8 + size + (size & 1);
+    }
+    return Err(bad("no data chunk"));
+}
+
+// Convert to mono or stereo at the output rate
+fn conform(channels: Vec<Vec<Samp>>, rate: u32) -> Vec<Vec<Samp>> {
+    let count = channels.len().min(CHANNELS);
+    return remix(channels, count).iter()
+        .map(|c| resample(c, rate, sample_rate().get()))
+        .collect();
 }
@@ -0,0 +1,258 @@
+use std::fs::File;
+use std::io::Write;
+use std::mem::take;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::output::{self, Destination, Format, Marker, OutputError, Settings, Sink};
+use crate::types::{Samp, CHANNELS, sample_rate};
+
+// Writes the broadcast as a run of separate files, one per half-inning or per so many seconds,
+// with an M3U playlist of them. Each file is finished off properly before the next starts, and
+// every frame lands in exactly one of them, so played back to back there are no gaps.
+
+#[derive(Clone, Copy, Debug)]
+pub enum Split {
+    HalfInning, // At each chapter after the first
+    Every(u64), // Seconds
+}
+
+impl FromStr for Split {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "inning" {
+            return Ok(Split::HalfInning);
+        }
+        return match s.parse::<u64>() {
+            Ok(seconds) if seconds > 0 => Ok(Split::Every(seconds)),
+            _ => Err("expected \"inning\" or a number of seconds".to_string())
+        };
+    }
+}
+
+pub struct SplitSink {
+    format: Format,
+    settings: Settings,
+    directory: PathBuf,
+    stem: String,
+    extension: String,
+    comments: Vec<(String, String)>,
+    split: Split,
+    playlist: Box<dyn Destination>,
+    current: Option<Box<dyn Sink>>, // Opened once there's something to write to it
+    piece: usize, // Counting from 1
+    piece_start: u64, // Frame the current file starts at
+    position: u64, // Frames written so far, over all the files
+    title: String, // For the current file
+    chapters: usize, // Chapters seen so far
+    boundaries: Vec<(u64, String)>, // Chapters still to split at, with the frame and title
+    pending: Vec<(u64, Marker)>, // Markers for audio that hasn't come in yet
+}
+
+impl SplitSink {
+
+    // Files are named after the path given, like game-001.ogg, with the playlist as game.m3u
+    pub fn new(path: &Path, format: Format, settings: Settings, comments: &[(String, String)], split: Split) -> Result<Self, OutputError> {
+        let directory = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("broadcast").to_string();
+        let mut playlist = File::create(directory.join(format!("{}.m3u", stem)))?;
+        write!(playlist, "#EXTM3U\n")?;
+        let mut sink = SplitSink {
+            format: format,
+            settings: settings,
+            directory: directory,
+            extension: path.extension().and_then(|e| e.to_str()).unwrap_or(format.extension()).to_string(),
+            stem: stem,
+            comments: comments.to_vec(),
+            split: split,
+            playlist: Box::new(playlist),
+            current: None,
+            piece: 1,
+            piece_start: 0,
+            position: 0,
+            title: String::new(),
+            chapters: 0,
+            boundaries: Vec::new(),
+            pending: Vec::new(),
+        };
+        sink.title = sink.base_title();
+        return Ok(sink);
+    }
+
+    fn file_name(&self) -> String {
+        return format!("{}-{:03}.{}", self.stem, self.piece, self.extension);
+    }
+
+    // Where the current file should end, and the title of the one after it
+    fn next_boundary(&self) -> Option<(u64, String)> {
+        return match self.split {
+            Split::HalfInning => self.boundaries.first().cloned(),
+            Split::Every(seconds) => {
+                let title = format!("{} (part {})", self.base_title(), self.piece + 1);
+                Some((self.piece_start + seconds * sample_rate().get() as u64, title))
+            }
+        };
+    }
+
+    fn base_title(&self) -> String {
+        return self.comments.iter().find(|(f, _)| f == "TITLE").map_or(self.stem.clone(), |(_, t)| t.clone());
+    }
+
+    fn open(&mut self) -> Result<(), OutputError> {
+        if self.current.is_none() {
+            let mut comments: Vec<(String, String)> = self.comments.iter()
+                .filter(|(f, _)| f != "TITLE")
+                .cloned()
+                .collect();
+            comments.insert(0, ("TITLE".to_string(), self.title.clone()));
+            comments.push(("TRACKNUMBER".to_string(), self.piece.to_string()));
+            let destination = output::open(Some(&self.directory.join(self.file_name())))?;
+            self.current = Some(self.format.sink(destination, &comments, &self.settings)?);
+        }
+        return Ok(());
+    }
+
+    // Write audio to the current file, with any markers that fall in it
+    fn write_part(&mut self, part: &[Samp]) -> Result<(), OutputError> {
+        self.open()?;
+        let end = self.position + (part.len() / CHANNELS) as u64;
+        let (now, later): (Vec<_>, Vec<_>) = take(&mut self.pending).into_iter().partition(|(f, _)| *f < end);
+        self.pending = later;
+        let sink = self.current.as_mut().unwrap();
+        for (frame, marker) in now {
+            sink.mark(frame.saturating_sub(self.piece_start), &marker)?;
+        }
+        sink.write(part)?;
+        self.position = end;
+        return Ok(());
+    }
+
+    // Finish off the current file and add it to the playlist
+    fn end_piece(&mut self) -> Result<(), OutputError> {
+        if let Some(sink) = self.current.take() {
+            sink.finish()?;
+            let seconds = (self.position - self.piece_start) as f64 / sample_rate().get() as f64;
+            write!(self.playlist, "#EXTINF:{},{}\n{}\n", seconds.round() as u64, self.title, self.file_name())?;
+            self.playlist.flush()?;
+        }
+        return Ok(());
+    }
+}
+
+impl Sink for SplitSink {
+    fn write(&mut self, buf: &[Samp]) -> Result<(), OutputError> {
+        let mut buf = buf;
+        loop {
+            let end = self.position + (buf.len() / CHANNELS) as u64;
+            match self.next_boundary() {
+                // A chapter right where the file starts renames it rather than leaving an empty file
+                Some((at, title)) if at <= self.piece_start => {
+                    self.boundaries.remove(0);
+                    self.title = title;
+                },
+                Some((at, title)) if at < end => {
+                    let (before, after) = buf.split_at((at.saturating_sub(self.position) as usize) * CHANNELS);
+                    self.write_part(before)?;
+                    if let Split::HalfInning = self.split {
+                        self.boundaries.remove(0);
+                    }
+                    self.end_piece()?;
+                    self.piece += 1;
+                    self.piece_start = self.position;
+                    self.title = title;
+                    buf = after;
+                },
+                _ => return self.write_part(buf)
+            }
+        }
+    }
+
+    fn mark(&mut self, frame: u64, marker: &Marker) -> Result<(), OutputError> {
+        if let (Split::HalfInning, Marker::Chapter(title)) = (self.split, marker) {
+            self.chapters += 1;
+            // The first chapter is the start of the broadcast, so it just names the first file
+            if self.chapters == 1 && self.current.is_none() {
+                self.title = title.clone();
+            } else {
+                self.boundaries.push((frame.max(self.position), title.clone()));
+            }
+        }
+        self.pending.push((frame, marker.clone()));
+        return Ok(());
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), OutputError> {
+        // Anything marked past the end still belongs in the last file
+        if !self.pending.is_empty() {
+            self.open()?;
+            let sink = self.current.as_mut().unwrap();
+            for (frame, marker) in take(&mut self.pending) {
+                sink.mark(frame.saturating_sub(self.piece_start), &marker)?;
+            }
+        }
+        self.end_piece()?;
+        self.playlist.flush()?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    // Splits frames of audio written in uneven blocks, with chapters marked up front, and gives
+    // back the title and length in frames of each piece, in playlist order
+    fn split(name: &str, split: Split, frames: usize, chapters: &[(u64, &str)]) -> Vec<(String, u64)> {
+        let directory = std::env::temp_dir().join(format!("bladio-split-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&directory).unwrap();
+        let comments = [("TITLE".to_string(), "Game".to_string())];
+        let mut sink = Box::new(SplitSink::new(&directory.join("game.raw"), Format::S16, Settings::default(),
+            &comments, split).unwrap());
+        for (frame, title) in chapters {
+            sink.mark(*frame, &Marker::Chapter(title.to_string())).unwrap();
+        }
+        let audio = vec![0.25; frames * CHANNELS];
+        for block in audio.chunks(777 * CHANNELS) {
+            sink.write(block).unwrap();
+        }
+        sink.finish().unwrap();
+
+        let playlist = fs::read_to_string(directory.join("game.m3u")).unwrap();
+        let mut lines = playlist.lines();
+        assert_eq!(lines.next(), Some("#EXTM3U"));
+        let mut pieces = Vec::new();
+        while let (Some(info), Some(file)) = (lines.next(), lines.next()) {
+            let title = info.split_once(',').unwrap().1.to_string();
+            let bytes = fs::metadata(directory.join(file)).unwrap().len();
+            pieces.push((title, bytes / (CHANNELS as u64 * 2)));
+        }
+        let files = fs::read_dir(&directory).unwrap().count();
+        fs::remove_dir_all(&directory).unwrap();
+        // Every piece is in the playlist once, and nothing else was written
+        assert_eq!(files, pieces.len() + 1, "{}", playlist);
+        return pieces;
+    }
+
+    #[test]
+    fn half_innings_split_at_chapters() {
+        let pieces = split("innings", Split::HalfInning, 10000,
+            &[(0, "Top 1"), (3000, "Bottom 1"), (7000, "Top 2"), (7000, "Bottom 2")]);
+        // Two chapters at once just rename the piece, rather than leaving an empty one
+        assert_eq!(pieces, [("Top 1".to_string(), 3000), ("Bottom 1".to_string(), 4000), ("Bottom 2".to_string(), 3000)]);
+    }
+
+    #[test]
+    fn timed_pieces_cover_every_frame() {
+        let second = sample_rate().get() as u64;
+        for frames in [second * 2 + 123, second * 2] {
+            let pieces = split(&format!("timed-{}", frames), Split::Every(1), frames as usize, &[]);
+            assert!(pieces.iter().all(|(_, length)| *length > 0), "{:?}", pieces);
+            assert_eq!(pieces.iter().map(|(_, length)| length).sum::<u64>(), frames);
+            assert_eq!(pieces[0], ("Game".to_string(), second));
+            assert_eq!(pieces[1], ("Game (part 2)".to_string(), second));
+        }
+    }
+}
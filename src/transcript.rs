@@ -0,0 +1,169 @@
+use std::io::Write;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::output::{Destination, Marker, OutputError, Sink, timestamp};
+use crate::types::{Samp, sample_rate};
+
+// Writes down what the announcers say, timed to the audio, as subtitles or a JSON list of cues.
+// Subtitles only have the speech; the JSON has sound effects, music and score changes too.
+// Cues are written as they happen, so the file can be followed during a live broadcast.
+
+#[derive(Clone, Copy)]
+pub enum TranscriptFormat {
+    Srt,
+    WebVtt,
+    Json,
+}
+
+impl TranscriptFormat {
+    pub fn from_path(path: &Path) -> Option<TranscriptFormat> {
+        return match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "srt" => Some(TranscriptFormat::Srt),
+            "vtt" => Some(TranscriptFormat::WebVtt),
+            "json" => Some(TranscriptFormat::Json),
+            _ => None
+        };
+    }
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    #[serde(rename = "type")]
+    kind: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    voice: Option<&'a str>,
+    text: &'a str,
+    start: u64, // In samples
+    end: u64,
+    start_time: f64, // In seconds
+    end_time: f64,
+}
+
+pub struct TranscriptSink {
+    destination: Box<dyn Destination>,
+    format: TranscriptFormat,
+    count: usize,
+}
+
+impl TranscriptSink {
+
+    pub fn new(mut destination: Box<dyn Destination>, format: TranscriptFormat) -> Result<Self, OutputError> {
+        match format {
+            TranscriptFormat::Srt => (),
+            TranscriptFormat::WebVtt => write!(destination, "WEBVTT\n\n")?,
+            TranscriptFormat::Json => write!(destination, "[")?,
+        }
+        return Ok(TranscriptSink {
+            destination: destination,
+            format: format,
+            count: 0,
+        });
+    }
+
+    fn write_entry(&mut self, entry: Entry) -> Result<(), OutputError> {
+        self.count += 1;
+        match self.format {
+            TranscriptFormat::Srt => write!(self.destination, "{}\n{} --> {}\n{}\n\n",
+                self.count, timestamp(entry.start, ','), timestamp(entry.end, ','), entry.text)?,
+            TranscriptFormat::WebVtt => write!(self.destination, "{} --> {}\n<v {}>{}\n\n",
+                timestamp(entry.start, '.'), timestamp(entry.end, '.'),
+                entry.voice.unwrap_or(""), escape_vtt(entry.text))?,
+            TranscriptFormat::Json => {
+                let separator = if self.count > 1 { "," } else { "" };
+                write!(self.destination, "{}\n  {}", separator, serde_json::to_string(&entry).unwrap())?;
+            }
+        }
+        self.destination.flush()?;
+        return Ok(());
+    }
+}
+
+impl Sink for TranscriptSink {
+    fn write(&mut self, _buf: &[Samp]) -> Result<(), OutputError> {
+        return Ok(());
+    }
+
+    fn mark(&mut self, frame: u64, marker: &Marker) -> Result<(), OutputError> {
+        let seconds = |frame: u64| frame as f64 / sample_rate().get() as f64;
+        let (kind, voice, text, start) = match marker {
+            Marker::Line { voice, text, frames } => ("line", Some(voice.name()), text.as_str(), frame - frames),
+            Marker::Sound(sound) => (sound.kind(), None, sound.which(), frame),
+            Marker::NowPlaying(text) => ("now_playing", None, text.as_str(), frame),
+            Marker::Chapter(title) => ("chapter", None, title.as_str(), frame),
+        };
+        if !matches!(marker, Marker::Line { .. }) && !matches!(self.format, TranscriptFormat::Json) {
+            return Ok(());
+        }
+        return self.write_entry(Entry {
+            kind: kind,
+            voice: voice,
+            text: text,
+            start: start,
+            end: frame,
+            start_time: seconds(start),
+            end_time: seconds(frame),
+        });
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), OutputError> {
+        if let TranscriptFormat::Json = self.format {
+            write!(self.destination, "\n]\n")?;
+        }
+        self.destination.flush()?;
+        return Ok(());
+    }
+}
+
+
+fn escape_vtt(text: &str) -> String {
+    return text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::announce_channel::{Stands, Voice};
+    use crate::output::Sound;
+    use crate::output::tests::Memory;
+
+    // A line ending 61.5s in that took two seconds to say, a cheer, then a line that starts at 0
+    fn transcribe(format: TranscriptFormat) -> String {
+        let rate = sample_rate().get() as u64;
+        let memory = Memory::default();
+        let mut sink = Box::new(TranscriptSink::new(Box::new(memory.clone()), format).unwrap());
+        let line = |voice: Voice, text: &str, frames: u64| Marker::Line { voice: voice, text: text.to_string(), frames: frames };
+        sink.mark(rate * 61 + rate / 2, &line(Voice::PlayByPlay, "Strike <three>", rate * 2)).unwrap();
+        sink.mark(rate * 62, &Marker::Sound(Sound::Cheer(Stands::Home))).unwrap();
+        sink.mark(rate / 2, &line(Voice::Colour, "Wow", rate / 2)).unwrap();
+        sink.finish().unwrap();
+        return String::from_utf8(memory.bytes()).unwrap();
+    }
+
+    #[test]
+    fn lines_are_timed_from_start_to_end() {
+        assert_eq!(transcribe(TranscriptFormat::Srt),
+            "1\n00:00:59,500 --> 00:01:01,500\nStrike <three>\n\n2\n00:00:00,000 --> 00:00:00,500\nWow\n\n");
+        assert_eq!(transcribe(TranscriptFormat::WebVtt),
+            "WEBVTT\n\n00:00:59.500 --> 00:01:01.500\n<v play_by_play>Strike &lt;three&gt;\n\n\
+            00:00:00.000 --> 00:00:00.500\n<v colour>Wow\n\n");
+
+        let rate = sample_rate().get() as u64;
+        let json: serde_json::Value = serde_json::from_str(&transcribe(TranscriptFormat::Json)).unwrap();
+        let entries = json.as_array().unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0]["type"], "line");
+        assert_eq!(entries[0]["voice"], "play_by_play");
+        assert_eq!(entries[0]["start"], rate * 59 + rate / 2);
+        assert_eq!(entries[0]["end"], rate * 61 + rate / 2);
+        assert_eq!(entries[0]["start_time"], 59.5);
+        assert_eq!(entries[0]["end_time"], 61.5);
+        assert_eq!(entries[1]["type"], "cheer");
+        assert_eq!(entries[1]["text"], "home");
+        assert_eq!(entries[1]["start"], entries[1]["end"]);
+        assert!(entries[1].get("voice").is_none());
+        assert_eq!(entries[2]["start"], 0);
+        assert_eq!(entries[2]["end_time"], 0.5);
+    }
+}
@@ -1,99 +1,279 @@
+use std::fmt;
+
 use pyo3::prelude::*;
 use pyo3::py_run;
 use pyo3::types::{PyIterator,IntoPyDict};
 
-use crate::types::Samp;
+use crate::resample::{Resampler, remix, decode_pcm, FORMAT_PCM};
+use crate::types::{Samp, sample_rate};
+
+#[derive(Clone, Copy, Debug)]
+pub struct Prosody {
+    pub length_scale: f32, // Speaking time, higher is slower
+    pub noise_scale: f32, // Variation in the voice
+    pub noise_w: f32, // Variation in phoneme lengths
+}
+
+impl Default for Prosody {
+    fn default() -> Self {
+        return Prosody {
+            length_scale: 0.8,
+            noise_scale: 0.667,
+            noise_w: 0.8,
+        };
+    }
+}
+
+impl Prosody {
+    // Speed up (or slow down, if < 1.0) by the given factor
+    pub fn faster(self, factor: f32) -> Prosody {
+        return Prosody {
+            length_scale: self.length_scale / factor,
+            ..self
+        };
+    }
+}
+
+const MAX_ATTEMPTS: u32 = 3;
+
+#[derive(Debug)]
+pub enum TtsError {
+    PythonError(PyErr),
+    EngineMissing(String),
+    BadAudio(String) // The engine gave back audio in a format we can't read
+}
+
+impl From<PyErr> for TtsError {
+    fn from(value: PyErr) -> Self {
+        return TtsError::PythonError(value);
+    }
+}
+
+impl fmt::Display for TtsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            TtsError::PythonError(err) => write!(f, "{}", err),
+            TtsError::EngineMissing(msg) => write!(f, "{}", msg),
+            TtsError::BadAudio(msg) => write!(f, "{}", msg)
+        };
+    }
+}
+
+// Make sure the TTS engine can be loaded before we start broadcasting
+pub fn check_engine(ssml: bool) -> Result<(), TtsError> {
+    return Python::with_gil(|py| {
+        py.import("mimic3_tts").map_err(|err| TtsError::EngineMissing(format!(
+            "Couldn't load Mimic 3 ({}). Is mycroft-mimic3-tts installed for the Python bladio is linked against?", err)))?;
+        if ssml {
+            py.import("opentts_abc.ssml").map_err(|err| TtsError::EngineMissing(format!(
+                "Couldn't load Mimic 3's SSML support ({}). Try running without --ssml.", err)))?;
+        }
+        return Ok(());
+    });
+}
+
+// Split "voice#speaker" into its parts
+fn parse_voice(voice_name: &str) -> (String, Option<String>) {
+    return match voice_name.split_once('#') {
+        Some((v, s)) => (v.to_string(), Some(s.to_string())),
+        None => (voice_name.to_string(), None)
+    };
+}
+
+// What we're currently trying to say, kept around so it can be retried
+struct Utterance {
+    text: String,
+    ssml: bool,
+    prosody: Prosody,
+    attempts: u32,
+    use_fallback: bool,
+    // Whether any audio has made it out yet. Past that point, a retry would repeat words.
+    started: bool
+}
+
+// Something that can say lines, a bit at a time
+pub trait Synthesizer {
+    fn is_speaking(&self) -> bool;
+    fn prosody(&self) -> Prosody;
+    fn say(&mut self, message: &str, prosody: Prosody) -> Result<(), TtsError>;
+    // With the markup left for the engine to interpret
+    fn say_ssml(&mut self, ssml: &str, prosody: Prosody) -> Result<(), TtsError>;
+    // Fills as much of buf as there's speech for, returning how much that was
+    fn next(&mut self, buf: &mut [Samp]) -> usize;
+}
 
 pub struct Speaker {
+    // Mimic 3 voice key, e.g. "en_US/vctk_low"
+    voice: String,
+    // Speaker within a multi-speaker voice, e.g. "p239"
+    speaker: Option<String>,
+    // Voice to use when this one keeps failing
+    fallback: Option<(String, Option<String>)>,
+    prosody: Prosody,
+    utterance: Option<Utterance>,
     utter_result: Option<PyObject>,
+    // Voices don't necessarily speak at our output rate
+    resampler: Option<Resampler>,
     buf: Vec<Samp>,
 }
 
 impl Speaker {
 
-    pub fn new() -> Self {
+    // Voices are named the same way as on the mimic3 command line, "voice#speaker"
+    pub fn new(voice_name: &str, fallback: Option<&str>, prosody: Prosody) -> Self {
+        let (voice, speaker) = parse_voice(voice_name);
         return Speaker {
+            voice: voice,
+            speaker: speaker,
+            fallback: fallback.map(parse_voice),
+            prosody: prosody,
+            utterance: None,
             utter_result: None,
+            resampler: None,
             buf: Vec::new()
         };
     }
 
-    pub fn is_speaking(&self) -> bool {
-        return !self.buf.is_empty() || self.utter_result.is_some();
+    // Start synthesis, retrying and falling back as needed
+    fn begin(&mut self, mut utterance: Utterance) -> Result<(), TtsError> {
+        self.utterance = None;
+        self.utter_result = None;
+        self.resampler = None;
+        loop {
+            utterance.attempts += 1;
+            match self.start(&utterance) {
+                Ok(results) => {
+                    self.utter_result = Some(results);
+                    self.utterance = Some(utterance);
+                    return Ok(());
+                },
+                Err(err) => {
+                    eprintln!("TTS attempt {} failed: {}", utterance.attempts, err);
+                    if !self.retry(&mut utterance) {
+                        return Err(err);
+                    }
+                }
+            }
+        }
     }
 
-    pub fn say(&mut self, message: &str) {
+    // Whether to have another go. Switches to the fallback voice once retries run out.
+    fn retry(&self, utterance: &mut Utterance) -> bool {
+        if utterance.attempts < MAX_ATTEMPTS {
+            return true;
+        } else if !utterance.use_fallback && self.fallback.is_some() {
+            eprintln!("Falling back to voice {}", self.fallback.as_ref().unwrap().0);
+            utterance.use_fallback = true;
+            utterance.attempts = 0;
+            return true;
+        }
+        return false;
+    }
+
+    fn start(&self, utterance: &Utterance) -> Result<PyObject, TtsError> {
+        let (voice, speaker) = match (&self.fallback, utterance.use_fallback) {
+            (Some((voice, speaker)), true) => (voice, speaker),
+            _ => (&self.voice, &self.speaker)
+        };
         return Python::with_gil(|py| {
             let mimic3 = py.import("mimic3_tts")?;
             let tts_class = mimic3.getattr("Mimic3TextToSpeechSystem")?;
             let opts_class = mimic3.getattr("Mimic3Settings")?;
 
             let kwargs = [
-                ("length_scale",0.8.into_py(py)),
+                ("voice",voice.clone().into_py(py)),
+                ("speaker",speaker.clone().into_py(py)),
+                ("length_scale",utterance.prosody.length_scale.into_py(py)),
                 ("use_cuda",false.into_py(py)),
-                ("noise_scale",0.667.into_py(py)),
-                ("noise_w",0.8.into_py(py)),
+                ("noise_scale",utterance.prosody.noise_scale.into_py(py)),
+                ("noise_w",utterance.prosody.noise_w.into_py(py)),
             ].into_py_dict(py);
             let opts = opts_class.call((), Some(kwargs))?;
 
             let tts = tts_class.call1((opts,))?;
-            tts.call_method0("begin_utterance")?;
-            tts.call_method1("speak_text", (message,))?;
-            let results = tts.call_method0("end_utterance")?;
-            self.utter_result = Some(results.into());
-
-            return Ok::<(), PyErr>(());
-        }).unwrap();
+            let results = if utterance.ssml {
+                let ssml_speaker = py.import("opentts_abc.ssml")?.getattr("SSMLSpeaker")?.call1((tts,))?;
+                ssml_speaker.call_method1("speak", (utterance.text.as_str(),))?
+            } else {
+                tts.call_method0("begin_utterance")?;
+                tts.call_method1("speak_text", (utterance.text.as_str(),))?;
+                tts.call_method0("end_utterance")?
+            };
+            return Ok(results.iter()?.into());
+        });
     }
 
-    pub fn next(&mut self, buf: &mut[Samp]) -> usize {
-        if self.buf.is_empty() {
-            // No half-filled buffer
-            return self.copy_from_tts(buf);
-        } else {
-            // Half-filled buffer
-            let mut samples_filled = self.partial_copy_from_stored_buf(buf);
-            if samples_filled < buf.len() {
-                samples_filled += self.copy_from_tts(&mut buf[samples_filled..]);
-            }
-            return samples_filled
+    // Synthesis failed part way through an utterance
+    fn recover(&mut self, err: TtsError) {
+        self.utter_result = None;
+        self.resampler = None;
+        match self.utterance.take() {
+            Some(mut utterance) if !utterance.started => {
+                eprintln!("TTS attempt {} failed: {}", utterance.attempts, err);
+                let text = utterance.text.clone();
+                if !self.retry(&mut utterance) || self.begin(utterance).is_err() {
+                    eprintln!("Skipping line {:?}", text);
+                }
+            },
+            Some(utterance) => eprintln!("TTS failed part way through {:?}: {}", utterance.text, err),
+            None => eprintln!("TTS failed: {}", err)
         }
     }
 
-    fn copy_from_tts(&mut self, buf: &mut [Samp]) -> usize {
-        let mut result_exhausted = false;
-        let res: PyResult<usize> =  Python::with_gil(|py| {
-            let samples_written = match &self.utter_result {
-                None => 0,
-                Some(results) => {
-                    let mut iter = PyIterator::from_object(py, results.as_ref(py))?;
-
-                    let mut samples_filled: usize = 0;
-
-                    while samples_filled < buf.len() {
-                        let n = iter.next();
-                        if n.is_some() {
-                            unsafe {
-                                let bytes = n.unwrap()?.getattr("audio_bytes")?.extract::<&[u8]>()?;
-                                let (_, shorts, _): (_, &[i16], _) = bytes.align_to::<i16>();
-                                samples_filled += self.partial_copy_and_convert(shorts, &mut buf[samples_filled..]);
-                            }
-                        } else {
-                            result_exhausted = true;
-                            break;
+    // Pull the next chunk of audio out of the engine and into self.buf
+    fn synthesize_more(&mut self) {
+        let res: Result<bool, TtsError> = Python::with_gil(|py| {
+            let results = match &self.utter_result {
+                None => return Ok(true),
+                Some(results) => results
+            };
+            let mut iter = PyIterator::from_object(py, results.as_ref(py))?;
+            loop {
+                match iter.next() {
+                    None => return Ok(true),
+                    Some(result) => {
+                        let result = result?;
+                        if !result.hasattr("audio_bytes")? {
+                            // Marks and the like
+                            continue;
                         }
+                        let rate: u32 = result.getattr("sample_rate_hz")?.extract()?;
+                        let width: usize = result.getattr("sample_width_bytes")?.extract()?;
+                        let channels: usize = result.getattr("num_channels")?.extract()?;
+                        let bytes: &[u8] = result.getattr("audio_bytes")?.extract()?;
+                        // Mimic 3 always hands back integer PCM
+                        let audio = decode_pcm(bytes, FORMAT_PCM, width, channels).ok_or_else(|| TtsError::BadAudio(
+                            format!("Can't read {}-byte samples from {} channels", width, channels)))?;
+                        let audio = remix(audio, 1).pop().unwrap_or_default();
+                        self.push_audio(&audio, rate);
+                        return Ok(false);
                     }
-                    samples_filled
                 }
-            };
-            return Ok(samples_written);
-            // TODO: Error checking
-            // return res.unwrap_or(0);
+            }
         });
-        if result_exhausted {
-            self.utter_result = None;
+        match res {
+            Ok(false) => if let Some(utterance) = self.utterance.as_mut() {
+                utterance.started = true;
+            },
+            Ok(true) => {
+                if let Some(mut resampler) = self.resampler.take() {
+                    resampler.flush(&mut self.buf);
+                }
+                self.utter_result = None;
+                self.utterance = None;
+            },
+            Err(err) => self.recover(err)
+        }
+    }
+
+    // Stash audio at the voice's own rate, converted to the output rate
+    fn push_audio(&mut self, audio: &[Samp], rate: u32) {
+        let output_rate = sample_rate().get();
+        if rate == output_rate {
+            self.buf.extend_from_slice(audio);
+        } else {
+            self.resampler.get_or_insert_with(|| Resampler::new(rate, output_rate)).process(audio, &mut self.buf);
         }
-        return res.unwrap();
     }
 
     // Copy what we can and stash the rest in self.buf
@@ -118,26 +298,38 @@ impl Speaker {
         }
         return samples_written;
     }
-    // Copy what we can and stash the rest in self.buf
-    fn partial_copy_and_convert(&mut self, from: &[i16], to: &mut [Samp]) -> usize {
-        if to.len() >= from.len() {
-            for (i, s) in from.iter().enumerate() {
-                to[i] = (*s as Samp) / 32768.0;
-            }
-            self.buf.truncate(0);
-            return from.len();
-        } else {
-            for (i, s) in from.iter().take(to.len()).enumerate() {
-                to[i] = (*s as Samp) / 32768.0;
-            }
-            self.buf.reserve(from.len() - to.len());
-            for s in from.iter().skip(to.len()) {
-                self.buf.push( (*s as Samp) / 32768.0 );
-            }
-            return to.len();
-        }
+}
+
+impl Synthesizer for Speaker {
+    fn is_speaking(&self) -> bool {
+        return !self.buf.is_empty() || self.utter_result.is_some();
+    }
+
+    fn prosody(&self) -> Prosody {
+        return self.prosody;
     }
 
+    fn say(&mut self, message: &str, prosody: Prosody) -> Result<(), TtsError> {
+        return self.begin(Utterance {
+            text: message.to_string(), ssml: false, prosody: prosody, attempts: 0, use_fallback: false, started: false
+        });
+    }
+
+    // Let Mimic 3 interpret the markup itself
+    fn say_ssml(&mut self, ssml: &str, prosody: Prosody) -> Result<(), TtsError> {
+        return self.begin(Utterance {
+            text: ssml.to_string(), ssml: true, prosody: prosody, attempts: 0, use_fallback: false, started: false
+        });
+    }
+
+    fn next(&mut self, buf: &mut[Samp]) -> usize {
+        let mut samples_filled = self.partial_copy_from_stored_buf(buf);
+        while samples_filled < buf.len() && self.utter_result.is_some() {
+            self.synthesize_more();
+            samples_filled += self.partial_copy_from_stored_buf(&mut buf[samples_filled..]);
+        }
+        return samples_filled;
+    }
 }
 
 pub fn _mimic_test() {
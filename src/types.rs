@@ -1,6 +1,36 @@
 use std::num::NonZeroU32;
-
-use nonzero_ext::nonzero;
+use std::sync::OnceLock;
 
 pub type Samp = f32;
-pub const SAMPLE_RATE: NonZeroU32 = nonzero!(22050u32);
\ No newline at end of file
+pub const DEFAULT_SAMPLE_RATE: u32 = 22050;
+// Output is interleaved stereo
+pub const CHANNELS: usize = 2;
+
+static SAMPLE_RATE: OnceLock<NonZeroU32> = OnceLock::new();
+
+// Random number streams, one for each thread that makes random choices
+pub const MAIN_STREAM: u64 = 0;
+pub const GAME_STREAM: u64 = 1;
+
+// Output sample rate. It's fixed the first time it's asked for, so anything that needs another
+// rate has to set it before then.
+pub fn sample_rate() -> NonZeroU32 {
+    return *SAMPLE_RATE.get_or_init(|| NonZeroU32::new(DEFAULT_SAMPLE_RATE).unwrap());
+}
+
+// Fails if the rate's already been fixed
+pub fn set_sample_rate(rate: NonZeroU32) -> Result<(), NonZeroU32> {
+    return SAMPLE_RATE.set(rate);
+}
+
+pub fn ms_to_samples(ms: u64) -> u64 {
+    return ms * sample_rate().get() as u64 / 1000;
+}
+
+// Seed this thread's random numbers from the broadcast's seed, if there is one, so the same seed
+// gives the same broadcast. Each thread uses its own stream so they don't all make the same choices.
+pub fn seed_thread(seed: Option<u64>, stream: u64) {
+    if let Some(seed) = seed {
+        fastrand::seed(seed ^ stream.wrapping_mul(0x9e3779b97f4a7c15));
+    }
+}
@@ -1,40 +1,45 @@
-use std::io::Write;
-
 use nonzero_ext::nonzero;
-use vorbis_rs::{VorbisEncoder,VorbisBitrateManagementStrategy, VorbisError};
-
-use crate::types::{Samp, SAMPLE_RATE};
-
-const BLOCK_SIZE: usize = 1024;
-
-pub fn output_to_vorbis<F, W>(mut supplier: F, writer: &mut W) -> Result<(), VorbisError>
-    where F: FnMut(&mut [Samp]) -> usize, W: Write
-{
-    let mut venc = VorbisEncoder::new(
-        0,
-        [("a","b")],
-        SAMPLE_RATE,
-        nonzero!(1u8),
-        VorbisBitrateManagementStrategy::QualityVbr { target_quality: 0.5 },
-        None,
-        writer)?;
-
-    let mut ibuf: [Samp; BLOCK_SIZE] = [0.0; BLOCK_SIZE];
-    let mut fbuf: [f32; BLOCK_SIZE] = [0.0; BLOCK_SIZE];
-    let mut empty_count = 0;
-    while empty_count < 3 {
-        let samples_filled = supplier(&mut ibuf);
-        // for (i, s) in ibuf[..samples_filled].iter().enumerate() {
-        //     fbuf[i] = (i16::from_le(*s) as f32) / 32768.0;
-        // }
+use vorbis_rs::{VorbisEncoder, VorbisBitrateManagementStrategy};
+
+use crate::output::{Destination, OutputError, Sink};
+use crate::types::{Samp, CHANNELS, sample_rate};
+
+pub struct VorbisSink {
+    encoder: VorbisEncoder<Box<dyn Destination>>,
+    // Interleaved from the mixer, split into channels for the encoder
+    planar: [Vec<Samp>; CHANNELS],
+}
+
+impl VorbisSink {
+
+    pub fn new(destination: Box<dyn Destination>, comments: &[(String, String)], quality: f32) -> Result<Self, OutputError> {
+        let encoder = VorbisEncoder::new(
+            0,
+            comments.iter().map(|(field, value)| (field, value)),
+            sample_rate(),
+            nonzero!(2u8),
+            VorbisBitrateManagementStrategy::QualityVbr { target_quality: quality },
+            None,
+            destination)?;
+        return Ok(VorbisSink {
+            encoder: encoder,
+            planar: Default::default(),
+        });
+    }
+}
 
-        venc.encode_audio_block([&ibuf[..samples_filled]])?;
-        if samples_filled < BLOCK_SIZE {
-            empty_count += 1;
+impl Sink for VorbisSink {
+    fn write(&mut self, buf: &[Samp]) -> Result<(), OutputError> {
+        for (c, channel) in self.planar.iter_mut().enumerate() {
+            channel.clear();
+            channel.extend(buf.iter().skip(c).step_by(CHANNELS));
         }
+        self.encoder.encode_audio_block(&self.planar)?;
+        return Ok(());
     }
 
-    venc.finish()?;
-
-    return Ok(());
-}
\ No newline at end of file
+    fn finish(self: Box<Self>) -> Result<(), OutputError> {
+        self.encoder.finish()?.flush()?;
+        return Ok(());
+    }
+}
@@ -0,0 +1,178 @@
+use crate::output::{Destination, Marker, OutputError, Sink, to_i16};
+use crate::types::{Samp, CHANNELS, sample_rate};
+
+const HEADER_SIZE: u32 = 44;
+const BYTES_PER_SAMPLE: u32 = 2;
+// What the sizes say until we know better. Most tools read this as "until the end of the stream".
+const UNKNOWN_SIZE: u32 = u32::MAX;
+
+// 16-bit PCM WAV. The sizes in the header are fixed up at the end if the destination is a file,
+// and chapters are added after the audio as cue points with labels.
+pub struct WavSink {
+    destination: Box<dyn Destination>,
+    data_size: u64,
+    bytes: Vec<u8>,
+    chapters: Vec<(u64, String)>,
+}
+
+impl WavSink {
+
+    pub fn new(mut destination: Box<dyn Destination>) -> Result<Self, OutputError> {
+        let rate = sample_rate().get();
+        let block_align = CHANNELS as u32 * BYTES_PER_SAMPLE;
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        header.extend(b"RIFF");
+        header.extend(UNKNOWN_SIZE.to_le_bytes());
+        header.extend(b"WAVE");
+        header.extend(b"fmt ");
+        header.extend(16u32.to_le_bytes());
+        header.extend(1u16.to_le_bytes()); // PCM
+        header.extend((CHANNELS as u16).to_le_bytes());
+        header.extend(rate.to_le_bytes());
+        header.extend((rate * block_align).to_le_bytes());
+        header.extend((block_align as u16).to_le_bytes());
+        header.extend((BYTES_PER_SAMPLE as u16 * 8).to_le_bytes());
+        header.extend(b"data");
+        header.extend(UNKNOWN_SIZE.to_le_bytes());
+        destination.write_all(&header)?;
+        return Ok(WavSink {
+            destination: destination,
+            data_size: 0,
+            bytes: Vec::new(),
+            chapters: Vec::new(),
+        });
+    }
+}
+
+impl Sink for WavSink {
+    fn write(&mut self, buf: &[Samp]) -> Result<(), OutputError> {
+        self.bytes.clear();
+        buf.iter().for_each(|s| self.bytes.extend(to_i16(*s).to_le_bytes()));
+        self.destination.write_all(&self.bytes)?;
+        self.data_size += self.bytes.len() as u64;
+        return Ok(());
+    }
+
+    fn mark(&mut self, frame: u64, marker: &Marker) -> Result<(), OutputError> {
+        if let Marker::Chapter(title) = marker {
+            self.chapters.push((frame, title.clone()));
+        }
+        return Ok(());
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), OutputError> {
+        let chunks = cue_chunks(&self.chapters);
+        let riff_size = self.data_size + (HEADER_SIZE as u64 - 8) + chunks.len() as u64;
+        // Fill in the sizes, unless they're too big for the header to hold. Anything after the
+        // audio would be taken for more audio if the sizes stay unknown, so the cues need them.
+        if riff_size < UNKNOWN_SIZE as u64 {
+            let data_size = self.data_size as u32;
+            if self.destination.rewrite(4, &(riff_size as u32).to_le_bytes())? {
+                self.destination.rewrite(HEADER_SIZE as u64 - 4, &data_size.to_le_bytes())?;
+                self.destination.write_all(&chunks)?;
+            }
+        }
+        self.destination.flush()?;
+        return Ok(());
+    }
+}
+
+// A cue chunk with a point for each chapter, and a list of labels to name them
+fn cue_chunks(chapters: &[(u64, String)]) -> Vec<u8> {
+    if chapters.is_empty() {
+        return Vec::new();
+    }
+    let mut cue = Vec::new();
+    cue.extend((chapters.len() as u32).to_le_bytes());
+    for (id, (frame, _)) in (1u32..).zip(chapters) {
+        cue.extend(id.to_le_bytes());
+        cue.extend((*frame as u32).to_le_bytes()); // Position
+        cue.extend(b"data");
+        cue.extend(0u32.to_le_bytes()); // Chunk start
+        cue.extend(0u32.to_le_bytes()); // Block start
+        cue.extend((*frame as u32).to_le_bytes()); // Sample offset
+    }
+
+    let mut labels = Vec::new();
+    labels.extend(b"adtl");
+    for (id, (_, title)) in (1u32..).zip(chapters) {
+        let mut label = Vec::new();
+        label.extend(id.to_le_bytes());
+        label.extend(title.as_bytes());
+        label.push(0);
+        labels.extend(chunk(b"labl", &label));
+    }
+
+    let mut out = chunk(b"cue ", &cue);
+    out.extend(chunk(b"LIST", &labels));
+    return out;
+}
+
+// Chunk header and body, padded to an even length
+fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len() + 9);
+    out.extend(id);
+    out.extend((body.len() as u32).to_le_bytes());
+    out.extend(body);
+    if body.len() % 2 == 1 {
+        out.push(0);
+    }
+    return out;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::tests::{Memory, test_signal};
+
+    // Each chunk's id and body, after the RIFF header
+    fn chunks(bytes: &[u8]) -> Vec<(&[u8], &[u8])> {
+        let mut chunks = Vec::new();
+        let mut pos = 12;
+        while pos + 8 <= bytes.len() {
+            let size = u32::from_le_bytes(bytes[pos + 4..pos + 8].try_into().unwrap()) as usize;
+            chunks.push((&bytes[pos..pos + 4], &bytes[pos + 8..pos + 8 + size]));
+            pos += 8 + size + (size & 1);
+        }
+        assert_eq!(pos, bytes.len());
+        return chunks;
+    }
+
+    #[test]
+    fn header_sizes_and_cues_are_filled_in() {
+        let frames = 5000;
+        let signal = test_signal(frames);
+        let memory = Memory::default();
+        let mut sink = Box::new(WavSink::new(Box::new(memory.clone())).unwrap());
+        sink.write(&signal).unwrap();
+        sink.mark(1234, &Marker::Chapter("Odd".to_string())).unwrap();
+        sink.finish().unwrap();
+
+        let bytes = memory.bytes();
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(&bytes[8..12], b"WAVE");
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize, bytes.len() - 8);
+
+        let chunks = chunks(&bytes);
+        let ids: Vec<&[u8]> = chunks.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, [b"fmt ", b"data", b"cue ", b"LIST"]);
+
+        let format = chunks[0].1;
+        let field = |at: usize| u16::from_le_bytes([format[at], format[at + 1]]) as u32;
+        assert_eq!(field(0), 1); // PCM
+        assert_eq!(field(2), CHANNELS as u32);
+        assert_eq!(u32::from_le_bytes(format[4..8].try_into().unwrap()), sample_rate().get());
+        assert_eq!(u32::from_le_bytes(format[8..12].try_into().unwrap()), sample_rate().get() * 4);
+        assert_eq!((field(12), field(14)), (4, 16));
+
+        let decoded: Vec<i16> = chunks[1].1.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+        let expected: Vec<i16> = signal.iter().map(|s| to_i16(*s)).collect();
+        assert_eq!(decoded, expected);
+
+        let cue = chunks[2].1;
+        assert_eq!(u32::from_le_bytes(cue[0..4].try_into().unwrap()), 1);
+        assert_eq!(u32::from_le_bytes(cue[24..28].try_into().unwrap()), 1234);
+        // The label: its cue id, then the title with a null on the end
+        assert_eq!(&chunks[3].1[4..], b"labl\x08\0\0\0\x01\0\0\0Odd\0");
+    }
+}